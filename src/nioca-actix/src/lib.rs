@@ -1,91 +1,123 @@
-use anyhow::Error;
-use der::Document;
-use nioca_common::x509::fetch_cert_x509;
-use nioca_common::{auth_token, req_client, ERR_TIMEOUT};
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::{Extensions, Payload};
+use actix_web::error::ErrorUnauthorized;
+use actix_web::rt::net::TcpStream;
+use actix_web::{FromRequest, HttpRequest};
+use async_trait::async_trait;
+use nioca_common::tls::resolver_server_config;
+use nioca_common::{spawn_renewal, CertSink};
 use rustls::ServerConfig;
+use std::any::Any;
+use std::future::{ready, Ready};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::watch;
-use tokio::time;
-use tracing::{error, info};
 
+pub use nioca_common::tls::NiocaCertResolver;
 pub use nioca_common::x509::CertX509Response;
-pub use nioca_common::NiocaConfig;
+pub use nioca_common::{
+    ClientAuth, NiocaConfig, NiocaError, PeerIdentity, RenewalHandle, RenewalStatus,
+};
 
 pub struct NiocaActix;
 
-impl NiocaActix {
-    pub async fn spawn(
-        config: NiocaConfig,
-    ) -> anyhow::Result<watch::Receiver<Option<ServerConfig>>> {
-        let (tx, rx) = watch::channel(None);
-
-        let api_key = if let Some(key) = &config.api_key_x509 {
-            key.to_string()
-        } else {
-            return Err(Error::msg("NIOCA_X509_API_KEY is not set"));
-        };
-        let url = if let Some(url) = &config.url_x509 {
-            url.to_string()
-        } else {
-            return Err(Error::msg("NIOCA_X509_CLIENT_ID is not set"));
-        };
+/// Swaps each renewed certificate into the resolver and publishes the resolver-backed
+/// `ServerConfig` once the first certificate is available.
+struct Sink {
+    resolver: Arc<NiocaCertResolver>,
+    server_config: Option<ServerConfig>,
+    tx: watch::Sender<Option<ServerConfig>>,
+}
 
-        actix_web::rt::spawn(async move {
-            let client = req_client(config.root_cert.clone());
-            let bearer = auth_token(&api_key);
-            let mut next_fetch;
+#[async_trait]
+impl CertSink for Sink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        self.resolver.update(certs)?;
+        if let Some(server_config) = self.server_config.take() {
+            self.tx.send_replace(Some(server_config));
+        }
+        Ok(())
+    }
+}
 
-            loop {
-                match fetch_cert_x509(&client, &url, &bearer).await {
-                    Ok((certs, not_after_sec)) => {
-                        next_fetch = Some(not_after_sec);
-                        Self::send_config(&certs, &tx).await;
-                    }
-                    Err(err) => {
-                        error!("{}", err);
-                        next_fetch = None;
-                    }
-                }
+/// Extracts the verified client certificate of the current connection.
+///
+/// Requires mutual TLS via `NiocaConfig::client_auth` and [NiocaActix::on_connect] to be
+/// registered with `HttpServer::on_connect`. Requests without a client certificate are
+/// rejected with `401 Unauthorized`. Use `Option<ClientCert>` with `ClientAuth::Optional`.
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub PeerIdentity);
 
-                let sleep_sec = if let Some(n) = next_fetch {
-                    n
-                } else {
-                    *ERR_TIMEOUT
-                };
-                info!("Fetching next certificate in {} seconds", sleep_sec);
-                time::sleep(Duration::from_secs(sleep_sec)).await;
-            }
-        });
+impl FromRequest for ClientCert {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
 
-        Ok(rx)
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let res = match req.conn_data::<PeerIdentity>() {
+            Some(peer) => Ok(Self(peer.clone())),
+            None => Err(ErrorUnauthorized("No valid client certificate")),
+        };
+        ready(res)
     }
+}
 
-    async fn send_config(certs: &CertX509Response, tx: &watch::Sender<Option<ServerConfig>>) {
-        let chain_doc = Self::pem_to_der(&certs.cert_chain).unwrap();
-        let chain = rustls::Certificate(chain_doc.to_vec());
-        let cert_doc = Self::pem_to_der(&certs.cert).unwrap();
-        let cert = rustls::Certificate(cert_doc.to_vec());
-        let certs_vec = vec![chain, cert];
+impl NiocaActix {
+    /// Spawns the certificate renewal.
+    ///
+    /// The `ServerConfig` is published once, as soon as the first certificate is available.
+    /// It is backed by a [NiocaCertResolver], which is updated in place with each renewal,
+    /// so a running actix server picks up new certificates without a restart.
+    ///
+    /// If `NiocaConfig::client_auth` is set, the config verifies client certificates against
+    /// the Nioca root.
+    pub async fn spawn(config: NiocaConfig) -> Result<RenewalHandle<ServerConfig>, NiocaError> {
+        Self::spawn_with_resolver(config, Arc::new(NiocaCertResolver::new())).await
+    }
 
-        let key_doc = Self::pem_to_der(&certs.key).unwrap();
-        let key = rustls::PrivateKey(key_doc.to_vec());
+    /// Same as [NiocaActix::spawn], but updates the given resolver, which makes it possible
+    /// to build a custom `ServerConfig` with [NiocaActix::server_config] upfront.
+    pub async fn spawn_with_resolver(
+        config: NiocaConfig,
+        resolver: Arc<NiocaCertResolver>,
+    ) -> Result<RenewalHandle<ServerConfig>, NiocaError> {
+        let server_config = Some(Self::server_config(&config, resolver.clone())?);
+        let (tx, rx) = watch::channel(None);
+        let sink = Sink {
+            resolver,
+            server_config,
+            tx,
+        };
+        let task = spawn_renewal(config, sink)?;
+        Ok(RenewalHandle::new(rx, task))
+    }
 
-        let cfg = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs_vec, key)
-            .map_err(|err| error!("Error building rustls ServerConfig: {}", err))
-            .expect("bad certificate/key");
+    /// Spawns the renewal and waits for the first certificate, see
+    /// [RenewalHandle::wait_ready].
+    pub async fn spawn_and_wait(
+        config: NiocaConfig,
+        timeout: Duration,
+    ) -> Result<RenewalHandle<ServerConfig>, NiocaError> {
+        Self::spawn(config).await?.into_ready(timeout).await
+    }
 
-        tx.send(Some(cfg)).expect("Sending rustls::ServerConfig");
+    /// Returns a ready to use `ServerConfig`, which always serves the latest certificate
+    /// from the given resolver and authenticates clients as configured.
+    pub fn server_config(
+        config: &NiocaConfig,
+        resolver: Arc<NiocaCertResolver>,
+    ) -> Result<ServerConfig, NiocaError> {
+        resolver_server_config(config, resolver)
     }
 
-    fn pem_to_der(pem: &str) -> anyhow::Result<Document> {
-        match Document::from_pem(pem) {
-            Ok(der) => Ok(der.1),
-            Err(err) => {
-                error!("{}", err);
-                Err(Error::msg(err))
+    /// Stores the verified client certificate of each new connection, which makes it
+    /// available via [ClientCert] or `HttpRequest::conn_data::<PeerIdentity>()`.
+    ///
+    /// Register it with `HttpServer::new(..).on_connect(NiocaActix::on_connect)`.
+    pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+        if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+            let (_, conn) = stream.get_ref();
+            if let Some(peer) = PeerIdentity::from_peer_certificates(conn.peer_certificates()) {
+                ext.insert(peer);
             }
         }
     }
@@ -1,73 +1,155 @@
-use axum_server::tls_rustls::RustlsConfig;
-use nioca_common::x509::fetch_cert_x509;
-use nioca_common::{auth_token, req_client, ERR_TIMEOUT};
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use nioca_common::tls::{server_cert_and_key, server_config_builder};
+use nioca_common::{spawn_renewal, CertSink};
+use rustls::server::WantsServerCert;
+use rustls::{ConfigBuilder, ServerConfig};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::watch;
-use tokio::time;
-use tracing::{error, info};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer;
 
 pub use nioca_common::x509::CertX509Response;
-pub use nioca_common::NiocaConfig;
+pub use nioca_common::{
+    ClientAuth, NiocaConfig, NiocaError, PeerIdentity, RenewalHandle, RenewalStatus,
+};
 
 pub struct NiocaAxum;
 
+/// Creates the `RustlsConfig` with the first certificate and reloads it in place with each
+/// renewal afterwards.
+struct Sink {
+    builder: ConfigBuilder<ServerConfig, WantsServerCert>,
+    tx: watch::Sender<Option<RustlsConfig>>,
+}
+
+#[async_trait]
+impl CertSink for Sink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        let server_config = Arc::new(NiocaAxum::server_config(self.builder.clone(), certs)?);
+
+        let current = self.tx.borrow().clone();
+        match current {
+            Some(rustls_config) => rustls_config.reload_from_config(server_config),
+            None => {
+                self.tx
+                    .send_replace(Some(RustlsConfig::from_config(server_config)));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl NiocaAxum {
-    pub async fn spawn(
-        config: NiocaConfig,
-    ) -> anyhow::Result<watch::Receiver<Option<RustlsConfig>>> {
+    /// Spawns the certificate renewal.
+    ///
+    /// The `RustlsConfig` is published once, as soon as the first certificate is available.
+    /// It is reloaded in place with each renewal, so the config passed to
+    /// `axum_server::bind_rustls` always serves the latest certificate.
+    ///
+    /// If `NiocaConfig::client_auth` is set, the config verifies client certificates against
+    /// the Nioca root. Serve it with a [NiocaAcceptor] to make them available to handlers.
+    pub async fn spawn(config: NiocaConfig) -> Result<RenewalHandle<RustlsConfig>, NiocaError> {
+        let builder = server_config_builder(&config)?;
         let (tx, rx) = watch::channel(None);
+        let task = spawn_renewal(config, Sink { builder, tx })?;
+        Ok(RenewalHandle::new(rx, task))
+    }
 
-        let api_key = if let Some(key) = &config.api_key_x509 {
-            key.to_string()
-        } else {
-            return Err(anyhow::Error::msg("NIOCA_X509_API_KEY is not set"));
-        };
-        let url = if let Some(url) = &config.url_x509 {
-            url.to_string()
-        } else {
-            return Err(anyhow::Error::msg("NIOCA_X509_CLIENT_ID is not set"));
-        };
-
-        tokio::spawn(async move {
-            let client = req_client(config.root_cert.clone());
-            let bearer = auth_token(&api_key);
-            let mut next_fetch;
-
-            loop {
-                match fetch_cert_x509(&client, &url, &bearer).await {
-                    Ok((certs, not_after_sec)) => {
-                        next_fetch = Some(not_after_sec);
-                        Self::send_config(&certs, &tx).await;
-                    }
-                    Err(err) => {
-                        error!("{}", err);
-                        next_fetch = None;
-                    }
-                }
-
-                let sleep_sec = if let Some(n) = next_fetch {
-                    n
-                } else {
-                    *ERR_TIMEOUT
-                };
-
-                info!("Fetching next certificate in {} seconds", sleep_sec);
-                time::sleep(Duration::from_secs(sleep_sec)).await;
-            }
-        });
+    /// Spawns the renewal and waits for the first certificate, see
+    /// [RenewalHandle::wait_ready].
+    pub async fn spawn_and_wait(
+        config: NiocaConfig,
+        timeout: Duration,
+    ) -> Result<RenewalHandle<RustlsConfig>, NiocaError> {
+        Self::spawn(config).await?.into_ready(timeout).await
+    }
 
-        Ok(rx)
+    /// Builds a new `ServerConfig` from the given certificate. Invalid material is rejected
+    /// without touching the currently active config.
+    fn server_config(
+        builder: ConfigBuilder<ServerConfig, WantsServerCert>,
+        certs: &CertX509Response,
+    ) -> Result<ServerConfig, NiocaError> {
+        let (chain, key) = server_cert_and_key(certs)?;
+
+        let mut cfg = builder.with_single_cert(chain, key).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Error building rustls ServerConfig: {}", err))
+        })?;
+        // the same protocols `RustlsConfig::from_pem` would set
+        cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(cfg)
+    }
+}
+
+/// A TLS acceptor for `axum_server`, which makes the verified client certificate of each
+/// connection available to handlers via the [ClientCert] extractor.
+///
+/// ```ignore
+/// axum_server::bind(addr)
+///     .acceptor(NiocaAcceptor::new(rustls_config))
+///     .serve(app.into_make_service())
+/// ```
+#[derive(Debug, Clone)]
+pub struct NiocaAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl NiocaAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for NiocaAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, Option<ClientCert>>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = inner.accept(stream, service).await?;
+            let peer = PeerIdentity::from_peer_certificates(stream.get_ref().1.peer_certificates());
+            Ok((stream, Extension(peer.map(ClientCert)).layer(service)))
+        })
     }
+}
 
-    async fn send_config(certs: &CertX509Response, tx: &watch::Sender<Option<RustlsConfig>>) {
-        let chain = format!("{}\n{}", certs.cert, certs.cert_chain);
-        let chain_vec = chain.as_bytes().to_vec();
-        let key_vec = certs.key.as_bytes().to_vec();
+/// Extracts the verified client certificate of the current connection.
+///
+/// Requires mutual TLS via `NiocaConfig::client_auth` and a server running with a
+/// [NiocaAcceptor]. Requests without a client certificate are rejected with
+/// `401 Unauthorized`. Use `Option<ClientCert>` with `ClientAuth::Optional`.
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub PeerIdentity);
 
-        let cfg = RustlsConfig::from_pem(chain_vec, key_vec)
-            .await
-            .expect("Building RustlsConfig from Nioca certs");
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientCert {
+    type Rejection = (StatusCode, &'static str);
 
-        tx.send(Some(cfg)).expect("Sending RustlsConfig");
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Option<ClientCert>>() {
+            Some(Some(cert)) => Ok(cert.clone()),
+            _ => Err((StatusCode::UNAUTHORIZED, "No valid client certificate")),
+        }
     }
 }
@@ -0,0 +1,72 @@
+use axum::routing::post;
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use nioca_common::fingerprint;
+use nioca_common::tls::pem_to_certs;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub struct Leaf {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub der: Vec<u8>,
+}
+
+pub fn root_ca() -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Nioca Test Root");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+pub fn leaf(root: &Certificate, name: &str) -> Leaf {
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = Certificate::from_params(params).unwrap();
+    let cert_pem = cert.serialize_pem_with_signer(root).unwrap();
+    let der = pem_to_certs(&cert_pem).unwrap().remove(0).0;
+    Leaf {
+        cert_pem,
+        key_pem: cert.serialize_private_key_pem(),
+        der,
+    }
+}
+
+/// Starts a fake Nioca, which answers with the given leaves in order and repeats the last one.
+pub async fn mock_nioca(
+    root: &Certificate,
+    leaves: Vec<Leaf>,
+    fetches: Arc<AtomicUsize>,
+) -> SocketAddr {
+    let server = leaf(root, "Nioca");
+    let tls = RustlsConfig::from_pem(server.cert_pem.into_bytes(), server.key_pem.into_bytes())
+        .await
+        .unwrap();
+
+    let root_pem = root.serialize_pem().unwrap();
+    let leaves = Arc::new(leaves);
+    let app = Router::new().route(
+        "/api/clients/x509/test/cert",
+        post(move || async move {
+            let idx = fetches.fetch_add(1, Ordering::SeqCst).min(leaves.len() - 1);
+            let leaf = &leaves[idx];
+            Json(serde_json::json!({
+                "cert": leaf.cert_pem,
+                "certFingerprint": fingerprint(&leaf.der),
+                "certChain": root_pem,
+                "key": leaf.key_pem,
+                "certFormat": "PEM",
+                "notAfter": chrono::Utc::now().timestamp() + 2,
+            }))
+        }),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum_server::from_tcp_rustls(listener, tls).serve(app.into_make_service()));
+    addr
+}
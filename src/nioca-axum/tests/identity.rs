@@ -0,0 +1,94 @@
+mod common;
+
+use axum::routing::get;
+use axum::Router;
+use common::{leaf, mock_nioca, root_ca};
+use nioca_axum::{ClientAuth, ClientCert, NiocaAcceptor, NiocaAxum, NiocaConfig};
+use nioca_common::{NiocaClientIdentity, RenewalPolicy};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+fn config(nioca_addr: SocketAddr, root_pem: &str) -> NiocaConfig {
+    NiocaConfig::builder()
+        .url(format!("https://localhost:{}", nioca_addr.port()))
+        .client_id_x509("test")
+        .api_key_x509("secret")
+        .root_pem(root_pem)
+        .client_auth(ClientAuth::Required)
+        .renewal_policy(RenewalPolicy {
+            renew_at: 0.5,
+            jitter: 0.0,
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(60),
+            backoff_initial: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(1),
+        })
+        .build()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_presents_renewed_identity() {
+    let root = root_ca();
+    let root_pem = root.serialize_pem().unwrap();
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let server_nioca = mock_nioca(&root, vec![leaf(&root, "Server")], fetches).await;
+    let handle =
+        NiocaAxum::spawn_and_wait(config(server_nioca, &root_pem), Duration::from_secs(10))
+            .await
+            .unwrap();
+    let rustls_config = handle.receiver().borrow().clone().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "https://localhost:{}/",
+        listener.local_addr().unwrap().port()
+    );
+    let app = Router::new().route(
+        "/",
+        get(|ClientCert(peer): ClientCert| async move { peer.subject }),
+    );
+    tokio::spawn(
+        axum_server::from_tcp(listener)
+            .acceptor(NiocaAcceptor::new(rustls_config))
+            .serve(app.into_make_service()),
+    );
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let leaves = vec![leaf(&root, "client-a"), leaf(&root, "client-b")];
+    let client_nioca = mock_nioca(&root, leaves, fetches).await;
+    let client_handle = NiocaClientIdentity::spawn_and_wait(
+        config(client_nioca, &root_pem),
+        Duration::from_secs(10),
+    )
+    .await
+    .unwrap();
+    let identity = NiocaClientIdentity::from(&client_handle);
+
+    let subject = |client: reqwest::Client| {
+        let url = url.clone();
+        async move { client.get(&url).send().await.unwrap().text().await.unwrap() }
+    };
+    assert_eq!(subject(identity.client().unwrap()).await, "CN=client-a");
+
+    // the first certificate is valid for 2 seconds and will be renewed after about 1 second
+    let mut swapped = false;
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(100)).await;
+        if subject(identity.client().unwrap()).await == "CN=client-b" {
+            swapped = true;
+            break;
+        }
+    }
+    assert!(
+        swapped,
+        "The client did not present the renewed certificate"
+    );
+
+    client_handle.shutdown().await.unwrap();
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use axum::routing::get;
+use axum::Router;
+use common::{leaf, mock_nioca, root_ca};
+use nioca_axum::{ClientAuth, ClientCert, NiocaAcceptor, NiocaAxum, NiocaConfig};
+use std::net::TcpListener;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_cert_is_required_and_extracted() {
+    let root = root_ca();
+    let root_pem = root.serialize_pem().unwrap();
+    let server = leaf(&root, "Server");
+    let client = leaf(&root, "my-client");
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let nioca_addr = mock_nioca(&root, vec![server], fetches).await;
+
+    let config = NiocaConfig::builder()
+        .url(format!("https://localhost:{}", nioca_addr.port()))
+        .client_id_x509("test")
+        .api_key_x509("secret")
+        .root_pem(root_pem.clone())
+        .client_auth(ClientAuth::Required)
+        .build()
+        .unwrap();
+
+    let handle = NiocaAxum::spawn_and_wait(config, Duration::from_secs(10))
+        .await
+        .unwrap();
+    let rustls_config = handle.receiver().borrow().clone().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let app = Router::new().route(
+        "/",
+        get(|ClientCert(peer): ClientCert| async move {
+            format!("{} {}", peer.subject, peer.sans.join(","))
+        }),
+    );
+    tokio::spawn(
+        axum_server::from_tcp(listener)
+            .acceptor(NiocaAcceptor::new(rustls_config))
+            .serve(app.into_make_service()),
+    );
+
+    let url = format!("https://localhost:{}/", port);
+    let root_cert = reqwest::Certificate::from_pem(root_pem.as_bytes()).unwrap();
+
+    let identity = format!("{}{}", client.cert_pem, client.key_pem);
+    let with_cert = reqwest::Client::builder()
+        .add_root_certificate(root_cert.clone())
+        .identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    let body = with_cert
+        .get(&url)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(body, "CN=my-client localhost");
+
+    let without_cert = reqwest::Client::builder()
+        .add_root_certificate(root_cert)
+        .build()
+        .unwrap();
+    assert!(without_cert.get(&url).send().await.is_err());
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use axum::routing::get;
+use axum::Router;
+use common::{leaf, mock_nioca, root_ca};
+use nioca_axum::{NiocaAxum, NiocaConfig};
+use nioca_common::RenewalPolicy;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// Connects to the given address and returns the DER of the leaf the server presented.
+async fn peer_cert(addr: SocketAddr, root_der: &[u8]) -> Vec<u8> {
+    let mut roots = RootCertStore::empty();
+    roots.add(&rustls::Certificate(root_der.to_vec())).unwrap();
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let tls = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    tls.get_ref().1.peer_certificates().unwrap()[0].0.clone()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_running_server_switches_certificates() {
+    let root = root_ca();
+    let root_der = root.serialize_der().unwrap();
+    let leaf_a = leaf(&root, "Leaf A");
+    let leaf_b = leaf(&root, "Leaf B");
+    let (der_a, der_b) = (leaf_a.der.clone(), leaf_b.der.clone());
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let nioca_addr = mock_nioca(&root, vec![leaf_a, leaf_b], fetches.clone()).await;
+
+    let config = NiocaConfig::builder()
+        .url(format!("https://localhost:{}", nioca_addr.port()))
+        .client_id_x509("test")
+        .api_key_x509("secret")
+        .root_pem(root.serialize_pem().unwrap())
+        .renewal_policy(RenewalPolicy {
+            renew_at: 0.5,
+            jitter: 0.0,
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(60),
+            backoff_initial: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(1),
+        })
+        .build()
+        .unwrap();
+
+    let handle = NiocaAxum::spawn_and_wait(config, Duration::from_secs(10))
+        .await
+        .unwrap();
+    let rustls_config = handle.receiver().borrow().clone().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().route("/", get(|| async { "ok" }));
+    tokio::spawn(
+        axum_server::from_tcp_rustls(listener, rustls_config).serve(app.into_make_service()),
+    );
+
+    assert_eq!(peer_cert(addr, &root_der).await, der_a);
+
+    // the first certificate is valid for 2 seconds and will be renewed after about 1 second
+    let mut switched = false;
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(100)).await;
+        if peer_cert(addr, &root_der).await == der_b {
+            switched = true;
+            break;
+        }
+    }
+    assert!(
+        switched,
+        "The running server did not switch to the renewed certificate"
+    );
+    assert!(fetches.load(Ordering::SeqCst) >= 2);
+
+    handle.shutdown().await.unwrap();
+}
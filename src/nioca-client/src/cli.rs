@@ -1,17 +1,27 @@
 use chrono::NaiveDateTime;
-use clap::{arg, Parser};
+use clap::Parser;
+use nioca_common::format::X509Bundle;
 use nioca_common::ssh::{fetch_cert_ssh, SshCertType, SshCertificateResponse};
-use nioca_common::x509::{fetch_cert_x509, CertX509Response};
-use nioca_common::{auth_token, req_client, NiocaConfig, ERR_TIMEOUT, VERSION};
+use nioca_common::x509::{fetch_cert_x509, CertX509Response, X509CertFormat};
+use nioca_common::{auth_token, fingerprint, req_client, NiocaConfig, Secret, VERSION};
+use ssh_key::{HashAlg, PublicKey};
 use std::fmt::Write;
 use std::io::ErrorKind;
-use std::ops::Sub;
+use std::path::{Path, PathBuf};
+#[cfg(target_family = "unix")]
 use std::process::Stdio;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
+#[cfg(target_family = "unix")]
 use tokio::process::Command;
 use tokio::{fs, time};
 
+use crate::files::{
+    save_versioned, write_atomic, write_atomic_perms, FilePerms, OutFile, Retention,
+};
+use crate::hooks::{Hooks, Renewed};
+use crate::output::{self, Output, SshContents, X509Contents};
+
 #[cfg(target_family = "unix")]
 const FILE_NAME_CONFIG: &str = "config";
 #[cfg(not(target_family = "unix"))]
@@ -22,6 +32,9 @@ const FILE_NAME_EXE: &str = "nioca-client";
 #[cfg(not(target_family = "unix"))]
 const FILE_NAME_EXE: &str = "nioca-client.exe";
 
+/// The SSH CA key, which is installed along with host certificates for `sshd`
+const PATH_SSH_CA_PUB: &str = "/etc/ssh/id_nioca_ca.pub";
+
 #[cfg(target_family = "unix")]
 const SEPARATOR: &str = "/";
 #[cfg(not(target_family = "unix"))]
@@ -90,6 +103,19 @@ pub(crate) struct CmdDaemonize {
     /// Output path for the fetched certificates
     #[arg(short, long, default_value = "./certs")]
     pub destination: String,
+
+    /// Output format for X509 certificates: pem, der or pkcs12.
+    /// Ignored, if an output spec is set with NIOCA_X509_OUTPUTS.
+    #[arg(long, default_value = "pem")]
+    pub x509_format: X509CertFormat,
+
+    /// The maximum number of X509 certificate versions to keep in the archive
+    #[arg(long, default_value = "5")]
+    pub x509_keep: usize,
+
+    /// Prune archived X509 certificate versions older than this many days
+    #[arg(long)]
+    pub x509_keep_days: Option<u64>,
 }
 
 /// Fetch an SSH certificate
@@ -145,6 +171,20 @@ pub(crate) struct CmdX509 {
     /// Output path for the fetched certificates
     #[arg(short, long, default_value = ".\\certs")]
     pub destination: String,
+
+    /// Output format: pem, der or pkcs12. PKCS12 archives are encrypted with
+    /// NIOCA_X509_PKCS12_PASSWORD from the config (default: empty).
+    /// Ignored, if an output spec is set with NIOCA_X509_OUTPUTS.
+    #[arg(long, default_value = "pem")]
+    pub format: X509CertFormat,
+
+    /// The maximum number of certificate versions to keep in the archive
+    #[arg(long, default_value = "5")]
+    pub keep: usize,
+
+    /// Prune archived certificate versions older than this many days
+    #[arg(long)]
+    pub keep_days: Option<u64>,
 }
 
 /// Serve a Single-Sign On UI on your localhost
@@ -200,7 +240,7 @@ pub async fn execute() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn get_config(path: &Option<String>) -> NiocaConfig {
+async fn get_config(path: &Option<String>) -> anyhow::Result<NiocaConfig> {
     if let Some(cfg) = path {
         dotenvy::from_filename(cfg).ok();
     } else {
@@ -222,7 +262,7 @@ async fn get_config(path: &Option<String>) -> NiocaConfig {
             }
         }
     }
-    NiocaConfig::from_env().await
+    Ok(NiocaConfig::try_from_env().await?)
 }
 
 #[cfg(target_family = "unix")]
@@ -262,18 +302,14 @@ async fn install_on_sys() -> anyhow::Result<()> {
 
 #NIOCA_X509_CLIENT_ID=
 #NIOCA_X509_API_KEY=
+#NIOCA_X509_PKCS12_PASSWORD=
     "#;
     let path_env = format!("{}/{}", config_dir, FILE_NAME_CONFIG);
 
     // only write the env file, if it does not already exist
     if File::open(&path_env).await.is_err() {
-        fs::write(&path_env, empty_env).await?;
-        #[cfg(target_family = "unix")]
-        {
-            use std::fs::Permissions;
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(&path_env, Permissions::from_mode(0o600)).await?;
-        }
+        // the env file will contain the API keys
+        write_atomic(&path_env, empty_env.as_bytes(), true).await?;
     }
 
     println!(
@@ -342,12 +378,14 @@ async fn install_on_sys() -> anyhow::Result<()> {
 
 #NIOCA_X509_CLIENT_ID=
 #NIOCA_X509_API_KEY=
+#NIOCA_X509_PKCS12_PASSWORD=
     "#;
     let path_env = format!("{}{}{}", config_dir, SEPARATOR, FILE_NAME_CONFIG);
 
     // only write the env file, if it does not already exist
     if File::open(&path_env).await.is_err() {
-        fs::write(&path_env, empty_env).await?;
+        // the env file will contain the API keys
+        write_atomic(&path_env, empty_env.as_bytes(), true).await?;
     }
 
     println!(
@@ -406,7 +444,7 @@ async fn install_systemd_service() -> anyhow::Result<()> {
 
     // create the service file
     let svc_path = format!("{}/{}", path, file_name);
-    fs::write(&svc_path, contents.as_bytes()).await?;
+    write_atomic(&svc_path, contents.as_bytes(), false).await?;
 
     systemd_reload_services().await?;
     systemd_enable_service(&file_name).await?;
@@ -505,7 +543,7 @@ async fn uninstall_from_sys() -> anyhow::Result<()> {
 }
 
 async fn fetch_root_ca(args: &CmdFetchRoot) -> anyhow::Result<()> {
-    let config = get_config(&args.config).await;
+    let config = get_config(&args.config).await?;
 
     let client = reqwest::ClientBuilder::new()
         .connect_timeout(Duration::from_secs(10))
@@ -541,9 +579,12 @@ async fn fetch_root_ca(args: &CmdFetchRoot) -> anyhow::Result<()> {
 
                             let destination = destination(dest);
                             fs::create_dir_all(&destination).await?;
-                            fs::write(format!("{}root.pem", destination), root_pem.as_bytes())
-                                .await
-                                .expect("Cannot write Root CA PEM to given destination");
+                            write_atomic(
+                                format!("{}root.pem", destination),
+                                root_pem.as_bytes(),
+                                false,
+                            )
+                            .await?;
                         } else {
                             match home::home_dir() {
                                 Some(path) => {
@@ -551,9 +592,7 @@ async fn fetch_root_ca(args: &CmdFetchRoot) -> anyhow::Result<()> {
                                     fs::create_dir_all(&p).await?;
                                     let target = format!("{}{}root.pem", p, SEPARATOR);
                                     println!("Saving root certificate to {}", target);
-                                    fs::write(&target, root_pem.as_bytes())
-                                        .await
-                                        .expect("Writing Root CA to $HOME");
+                                    write_atomic(&target, root_pem.as_bytes(), false).await?;
 
                                     #[cfg(target_family = "unix")]
                                     println!(
@@ -600,6 +639,9 @@ async fn daemonize(args: &CmdDaemonize) -> anyhow::Result<()> {
     let cmd_x509 = CmdX509 {
         config: args.config.clone(),
         destination: args.destination.clone(),
+        format: args.x509_format,
+        keep: args.x509_keep,
+        keep_days: args.x509_keep_days,
     };
     tokio::spawn(async move {
         if let Err(err) = fetch_x509(cmd_x509, true).await {
@@ -615,7 +657,7 @@ async fn daemonize(args: &CmdDaemonize) -> anyhow::Result<()> {
 }
 
 async fn fetch_ssh(args: CmdSsh, daemonize: bool) -> anyhow::Result<()> {
-    let config = get_config(&args.config).await;
+    let config = get_config(&args.config).await?;
 
     let api_key = if let Some(key) = &config.api_key_ssh {
         key
@@ -628,39 +670,69 @@ async fn fetch_ssh(args: CmdSsh, daemonize: bool) -> anyhow::Result<()> {
         return Err(anyhow::Error::msg("NIOCA_SSH_CLIENT_ID is not set"));
     };
 
-    let client = req_client(config.root_cert.clone());
-    let bearer = auth_token(api_key);
+    let client = req_client(&config)?;
+    let bearer = Secret::new(auth_token(api_key.expose_secret()));
+    let hooks = Hooks::from_env("NIOCA_SSH")?;
+    let outputs = output::load::<SshContents>("NIOCA_SSH")
+        .await?
+        .unwrap_or_else(output::default_ssh);
 
-    let mut next_fetch = *ERR_TIMEOUT;
+    let policy = &config.renewal;
+    let mut failures = 0;
     loop {
         println!("\nFetching SSH certificate from {}", url);
 
-        match fetch_cert_ssh(&client, url, &bearer).await {
+        let next_fetch = match fetch_cert_ssh(&client, url, bearer.expose_secret()).await {
             Ok(resp) => {
                 let destination = destination(&args.destination);
-                match save_files_ssh(&destination, &resp).await {
-                    Ok(valid_until) => {
-                        let now = system_to_naive_datetime(SystemTime::now());
-                        let diff = valid_until.sub(now).num_seconds() as u64;
-                        next_fetch = diff * 90 / 100;
+                match save_files_ssh(&destination, &resp, &outputs, &config).await {
+                    Ok(renewed) => {
+                        let installed = if daemonize || args.install {
+                            async {
+                                install_host_ssh(&resp).await?;
+                                install_known_host(&resp).await
+                            }
+                            .await
+                        } else {
+                            Ok(())
+                        };
+                        // the files have been saved either way
+                        hooks.run(&renewed).await;
+
+                        match installed {
+                            Ok(()) => {
+                                failures = 0;
+                                policy.next_renewal(renewed.not_after)
+                            }
+                            Err(err) if !daemonize => return Err(err),
+                            Err(err) => {
+                                eprintln!("Error installing SSH certificate: {}", err);
+                                failures += 1;
+                                policy.next_retry(failures, None)
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("Error saving SSH certificate: {}", err);
+                        failures += 1;
+                        policy.next_retry(failures, None)
                     }
-                    Err(err) => eprintln!("Error fetching SSH certificate: {}", err),
-                }
-
-                if daemonize || args.install {
-                    install_host_ssh(&resp).await?;
-                    install_known_host(&resp).await?;
                 }
             }
             Err(err) => {
                 eprintln!("{}", err);
+                failures += 1;
+                policy.next_retry(failures, err.retry_after())
             }
-        }
+        };
 
         match daemonize {
             true => {
-                println!("Fetching next SSH certificate in {} seconds", next_fetch);
-                time::sleep(Duration::from_secs(next_fetch)).await;
+                println!(
+                    "Fetching next SSH certificate in {} seconds",
+                    next_fetch.as_secs()
+                );
+                time::sleep(next_fetch).await;
             }
             false => {
                 return Ok(());
@@ -670,7 +742,7 @@ async fn fetch_ssh(args: CmdSsh, daemonize: bool) -> anyhow::Result<()> {
 }
 
 async fn fetch_x509(args: CmdX509, daemonize: bool) -> anyhow::Result<()> {
-    let config = get_config(&args.config).await;
+    let config = get_config(&args.config).await?;
 
     let api_key = if let Some(key) = &config.api_key_x509 {
         key
@@ -683,32 +755,64 @@ async fn fetch_x509(args: CmdX509, daemonize: bool) -> anyhow::Result<()> {
         return Err(anyhow::Error::msg("NIOCA_X509_CLIENT_ID is not set"));
     };
 
-    let client = req_client(config.root_cert.clone());
-    let bearer = auth_token(api_key);
+    let client = req_client(&config)?;
+    let bearer = Secret::new(auth_token(api_key.expose_secret()));
+
+    let retention = Retention {
+        keep: args.keep,
+        max_age: args
+            .keep_days
+            .map(|days| Duration::from_secs(days * 24 * 3600)),
+    };
 
-    let mut next_fetch = *ERR_TIMEOUT;
+    let hooks = Hooks::from_env("NIOCA_X509")?;
+    let outputs = output::load::<X509Contents>("NIOCA_X509").await?;
+
+    let policy = &config.renewal;
+    let mut failures = 0;
     loop {
         println!("\nFetching X509 certificate from {}", url);
 
-        match fetch_cert_x509(&client, url, &bearer).await {
-            Ok((certs, not_after_sec)) => {
+        let next_fetch = match fetch_cert_x509(&client, url, bearer.expose_secret()).await {
+            Ok(certs) => {
                 let destination = destination(&args.destination);
-                match save_files_x509(&destination, &certs).await {
-                    Ok(_) => {
-                        next_fetch = not_after_sec;
+                match save_files_x509(
+                    &destination,
+                    &certs,
+                    args.format,
+                    outputs.as_deref(),
+                    &retention,
+                    &config,
+                )
+                .await
+                {
+                    Ok(renewed) => {
+                        hooks.run(&renewed).await;
+
+                        failures = 0;
+                        policy.next_renewal(renewed.not_after)
+                    }
+                    Err(err) => {
+                        eprintln!("Error fetching X509 certificate: {}", err);
+                        failures += 1;
+                        policy.next_retry(failures, None)
                     }
-                    Err(err) => eprintln!("Error fetching X509 certificate: {}", err),
                 }
             }
             Err(err) => {
                 eprintln!("{}", err);
+                failures += 1;
+                policy.next_retry(failures, err.retry_after())
             }
-        }
+        };
 
         match daemonize {
             true => {
-                println!("Fetching next X509 certificate in {} seconds", next_fetch);
-                time::sleep(Duration::from_secs(next_fetch)).await;
+                println!(
+                    "Fetching next X509 certificate in {} seconds",
+                    next_fetch.as_secs()
+                );
+                time::sleep(next_fetch).await;
             }
             false => {
                 return Ok(());
@@ -717,52 +821,45 @@ async fn fetch_x509(args: CmdX509, daemonize: bool) -> anyhow::Result<()> {
     }
 }
 
-pub fn fingerprint(value: &[u8]) -> String {
-    let digest = ring::digest::digest(&ring::digest::SHA256, value);
-    let fingerprint = hex::encode(digest.as_ref());
-    let fingerprint_full = format!("sha256:{}", fingerprint);
-    fingerprint_full
-}
-
+/// Saves the SSH certificate and returns the saved files with its `valid_before` as the
+/// `not_after`.
+///
+/// The certificate is validated first and nothing is written, if it is rejected. The caller
+/// must only install the certificate after this succeeded.
 async fn save_files_ssh(
     out_dir: &str,
     certs: &SshCertificateResponse,
-) -> anyhow::Result<NaiveDateTime> {
+    outputs: &[Output<SshContents>],
+    config: &NiocaConfig,
+) -> anyhow::Result<Renewed> {
+    let mut ca_fingerprints = config.ssh_ca_fingerprints.clone();
+    if ca_fingerprints.is_empty() {
+        ca_fingerprints.extend(installed_ssh_ca_fingerprint().await);
+    }
+    let cert = certs.validate(
+        &ca_fingerprints,
+        config.ssh_trust_response_ca,
+        config.ssh_cert_type.as_ref(),
+        config.clock_skew,
+    )?;
+
     let out_dir = format!("{}ssh{}", out_dir, SEPARATOR);
     fs::create_dir_all(&out_dir).await?;
 
     println!("Saving SSH certificate to {}", out_dir);
-    let path_key = format!("{}id_nioca", out_dir);
-    fs::write(&path_key, certs.host_key_pair.id.as_bytes())
-        .await
-        .expect("Writing Certificate");
-    fs::write(
-        format!("{}id_nioca.pub", out_dir),
-        certs.host_key_pair.id_pub.as_bytes(),
-    )
-    .await
-    .expect("Writing Certificate");
-    fs::write(
-        format!("{}id_nioca_ca.pub", out_dir),
-        certs.user_ca_pub.as_bytes(),
-    )
-    .await
-    .expect("Writing Certificate");
-
-    // the key must only be readable by the current user
-    // #[cfg(target_family = "unix")]
-    // {
-    //     use std::fs::Permissions;
-    //     use std::os::unix::fs::PermissionsExt;
-    //     fs::set_permissions(&path_key, Permissions::from_mode(0o600)).await?;
-    // }
-    // the key must only be readable by the current user
-    set_perm_user_only(&path_key).await?;
+    let files = output::render_ssh(outputs, certs);
+    for file in &files {
+        // the key must only be readable by the current user
+        write_atomic_perms(
+            format!("{}{}", out_dir, file.name),
+            &file.contents,
+            &file.perms,
+        )
+        .await?;
+    }
 
     println!("SSH Certificate saved successfully.");
 
-    let cert = ssh_key::Certificate::from_openssh(&certs.host_key_pair.id_pub)
-        .expect("Cannot parse SSH Certificate");
     let valid_until = system_to_naive_datetime(cert.valid_before_time());
 
     if certs.host_key_pair.typ == Some(SshCertType::User) {
@@ -781,10 +878,15 @@ async fn save_files_ssh(
             valid_until,
         );
 
-        println!(
-            "Connect to your target with:\n\nssh -i {} USER@IP\n",
-            path_key
-        );
+        let key = outputs
+            .iter()
+            .find(|output| output.contents == SshContents::Key);
+        if let Some(key) = key {
+            println!(
+                "Connect to your target with:\n\nssh -i {}{} USER@IP\n",
+                out_dir, key.filename
+            );
+        }
     } else {
         println!(
             r#"
@@ -800,132 +902,141 @@ async fn save_files_ssh(
         );
     }
 
-    Ok(valid_until)
+    let files = files
+        .iter()
+        .map(|file| Path::new(&out_dir).join(&file.name))
+        .collect();
+    Ok(Renewed {
+        typ: "ssh",
+        dir: PathBuf::from(&out_dir),
+        files,
+        not_after: cert.valid_before() as i64,
+        fingerprint: cert.public_key().fingerprint(HashAlg::Sha256).to_string(),
+    })
 }
 
-async fn save_files_x509(out_dir: &str, certs: &CertX509Response) -> anyhow::Result<()> {
-    let out_dir = format!("{}x509{}", out_dir, SEPARATOR);
-    let out_na = format!("{}{}", out_dir, certs.not_after);
-    fs::create_dir_all(&out_na).await?;
+async fn save_files_x509(
+    out_dir: &str,
+    certs: &CertX509Response,
+    format: X509CertFormat,
+    outputs: Option<&[Output<X509Contents>]>,
+    retention: &Retention,
+    config: &NiocaConfig,
+) -> anyhow::Result<Renewed> {
+    let pkcs12_password = config.pkcs12_password.as_ref().map(Secret::expose_secret);
+    let certs = certs.to_format(X509CertFormat::Pem, pkcs12_password)?;
+    // never overwrite a working certificate with broken material
+    let info = certs.validate(config.root_pem.as_deref(), config.clock_skew)?;
+    let files = match outputs {
+        Some(outputs) => output::render_x509(outputs, &certs, pkcs12_password)?,
+        None => x509_files(&certs, format, pkcs12_password)?,
+    };
 
+    let out_dir = format!("{}x509", out_dir);
     println!("Saving certificates to {}", out_dir);
-    fs::write(format!("{}cert.pem", out_dir), certs.cert.as_bytes())
-        .await
-        .expect("Writing Certificate");
-    fs::write(format!("{}chain.pem", out_dir), certs.cert_chain.as_bytes())
-        .await
-        .expect("Writing Certificate");
-    let path_key = format!("{}key.pem", out_dir);
-    fs::write(&path_key, certs.key.as_bytes())
-        .await
-        .expect("Writing Certificate");
-
-    println!("Saving certificates to {}", out_na);
-    fs::write(
-        format!("{}{}cert.pem", out_na, SEPARATOR),
-        certs.cert.as_bytes(),
-    )
-    .await
-    .expect("Writing Certificate");
-    fs::write(
-        format!("{}{}chain.pem", out_na, SEPARATOR),
-        certs.cert_chain.as_bytes(),
-    )
-    .await
-    .expect("Writing Certificate");
-    let path_key_na = format!("{}{}key.pem", out_na, SEPARATOR);
-    fs::write(&path_key_na, certs.key.as_bytes())
-        .await
-        .expect("Writing Certificate");
-
-    // the key must only be readable by the current user
-    set_perm_user_only(&path_key).await?;
-
-    Ok(())
-}
-
-#[cfg(target_family = "unix")]
-async fn set_perm_user_only(path: &str) -> anyhow::Result<()> {
-    use std::os::unix::fs::PermissionsExt;
-
-    let file = File::open(path).await?;
-    let mut perms = file.metadata().await?.permissions();
-    perms.set_mode(0o600);
-    file.set_permissions(perms).await?;
+    let version = save_versioned(Path::new(&out_dir), certs.not_after, &files, retention).await?;
 
-    let file = File::open(path).await?;
-    let mut perms = file.metadata().await?.permissions();
-    perms.set_mode(0o600);
-    file.set_permissions(perms).await?;
+    println!(
+        "X509 Certificate saved successfully to {} - the current version is always available in {}{}live",
+        version.display(),
+        out_dir,
+        SEPARATOR,
+    );
+    println!(
+        r#"
+    Subject: {}
+    Alternative names: {:?}
+    Issuer: {}
+    Serial: {}
+    Key algorithm: {}
+    Fingerprint: {}
+    Certificate valid from: {:?}
+    Certificate valid until: {:?}
+        "#,
+        info.subject,
+        info.sans,
+        info.issuer,
+        info.serial,
+        info.key_algorithm,
+        info.fingerprint,
+        system_to_naive_datetime(UNIX_EPOCH + Duration::from_secs(info.not_before as u64)),
+        system_to_naive_datetime(UNIX_EPOCH + Duration::from_secs(info.not_after as u64)),
+    );
 
-    Ok(())
+    let live = Path::new(&out_dir).join("live");
+    Ok(Renewed {
+        typ: "x509",
+        files: files.iter().map(|file| live.join(&file.name)).collect(),
+        dir: live,
+        not_after: info.not_after,
+        fingerprint: info.fingerprint,
+    })
 }
 
-#[cfg(not(target_family = "unix"))]
-async fn set_perm_user_only(path: &str) -> anyhow::Result<()> {
-    // lets get our username first
-    let out = Command::new("powershell.exe")
-        .arg("-c")
-        .arg("$env:UserName")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await?;
-    let username = String::from_utf8_lossy(&out.stdout).trim().to_string();
-
-    println!("Setting access only to {} for {}", username, path);
-
-    // remove inheritance
-    if !Command::new("icacls.exe")
-        .arg(path)
-        .arg("/inheritance:r")
-        .stdout(Stdio::piped())
-        .spawn()?
-        .wait()
-        .await?
-        .success()
-    {
-        eprintln!("Error removing file inheritance for {}", path);
-    }
+/// Returns each file of the X509 certificate in the given output format.
+fn x509_files(
+    certs: &CertX509Response,
+    format: X509CertFormat,
+    pkcs12_password: Option<&str>,
+) -> anyhow::Result<Vec<OutFile>> {
+    let files = match format {
+        X509CertFormat::Pem => {
+            let pem = certs.to_format(X509CertFormat::Pem, pkcs12_password)?;
+            vec![
+                ("cert.pem".to_string(), pem.cert.into_bytes().into(), false),
+                (
+                    "chain.pem".to_string(),
+                    pem.cert_chain.into_bytes().into(),
+                    false,
+                ),
+                (
+                    "key.pem".to_string(),
+                    pem.key.expose_secret().as_bytes().to_vec().into(),
+                    true,
+                ),
+            ]
+        }
+        X509CertFormat::Der => {
+            let bundle = X509Bundle::from_response(certs, pkcs12_password)?;
+            let mut files = vec![
+                (
+                    "cert.der".to_string(),
+                    bundle.leaf().0.clone().into(),
+                    false,
+                ),
+                ("key.der".to_string(), bundle.key.0.clone().into(), true),
+            ];
+            for (i, cert) in bundle.chain[1..].iter().enumerate() {
+                files.push((format!("chain-{}.der", i), cert.0.clone().into(), false));
+            }
+            files
+        }
+        X509CertFormat::PKCS12 => {
+            let bundle = X509Bundle::from_response(certs, pkcs12_password)?;
+            let p12 = bundle.to_pkcs12(pkcs12_password.unwrap_or_default())?;
+            vec![("cert.p12".to_string(), p12.into(), true)]
+        }
+    };
 
-    // set ownership
-    if !Command::new("icacls.exe")
-        .arg(path)
-        .arg("/grant")
-        .arg(format!("{}:F", username))
-        .stdout(Stdio::piped())
-        .spawn()?
-        .wait()
-        .await?
-        .success()
-    {
-        eprintln!("Error granting user full access to {}", path);
-    }
-    if !Command::new("takeown.exe")
-        .arg("/F")
-        .arg(path)
-        .stdout(Stdio::piped())
-        .spawn()?
-        .wait()
-        .await?
-        .success()
-    {
-        eprintln!("Error taking ownership for {}", path);
-    }
-    if !Command::new("icacls.exe")
-        .arg(path)
-        .arg("/grant:r")
-        .arg(format!("{}:F", username))
-        .stdout(Stdio::piped())
-        .spawn()?
-        .wait()
-        .await?
-        .success()
-    {
-        eprintln!("Error granting 'read' to {}", path);
-    }
+    let files = files
+        .into_iter()
+        .map(|(name, contents, secret)| OutFile {
+            name,
+            contents,
+            perms: FilePerms {
+                secret,
+                ..Default::default()
+            },
+        })
+        .collect();
+    Ok(files)
+}
 
-    Ok(())
+/// Returns the fingerprint of the SSH CA key, which has been installed by a former run.
+async fn installed_ssh_ca_fingerprint() -> Option<String> {
+    let ca_pub = fs::read_to_string(PATH_SSH_CA_PUB).await.ok()?;
+    let key = PublicKey::from_openssh(ca_pub.trim()).ok()?;
+    Some(key.fingerprint(HashAlg::Sha256).to_string())
 }
 
 async fn install_host_ssh(certs: &SshCertificateResponse) -> anyhow::Result<()> {
@@ -939,20 +1050,27 @@ async fn install_host_ssh(certs: &SshCertificateResponse) -> anyhow::Result<()>
 
     #[cfg(target_family = "unix")]
     {
-        let path_ca_pub = "/etc/ssh/id_nioca_ca.pub";
-        // fs::write(&path_ca_pub, certs.user_ca_pub.as_bytes()).await?;
-        if let Err(err) = fs::write(&path_ca_pub, certs.user_ca_pub.as_bytes()).await {
-            if err.kind() == ErrorKind::PermissionDenied {
+        if let Err(err) = write_atomic(PATH_SSH_CA_PUB, certs.user_ca_pub.as_bytes(), false).await {
+            let denied = err
+                .downcast_ref::<std::io::Error>()
+                .map(|err| err.kind() == ErrorKind::PermissionDenied)
+                .unwrap_or(false);
+            if denied {
                 let msg = "You can only install SSH Host certificates as root - exiting early";
                 eprintln!("{}", msg);
                 return Err(anyhow::Error::msg(msg));
             }
+            return Err(err);
         }
 
         let path_id = "/etc/ssh/id_nioca_host";
-        fs::write(&path_id, certs.host_key_pair.id.as_bytes()).await?;
         // the key must only be readable by the current user
-        set_perm_user_only(path_id).await?;
+        write_atomic(
+            path_id,
+            certs.host_key_pair.id.expose_secret().as_bytes(),
+            true,
+        )
+        .await?;
 
         // TODO `sshd` prints out a weird error when you log in with certificates, even though everything works fine:
         // error: Public key for /etc/ssh/id_nioca_host does not match private key
@@ -960,7 +1078,7 @@ async fn install_host_ssh(certs: &SshCertificateResponse) -> anyhow::Result<()>
         // -> we do not have (and need) any public key, but the certificate instead
         // -> needs another sshd config adjustment here?
         let path_id_pub = "/etc/ssh/id_nioca_host.pub";
-        fs::write(&path_id_pub, certs.host_key_pair.id_pub.as_bytes()).await?;
+        write_atomic(path_id_pub, certs.host_key_pair.id_pub.as_bytes(), false).await?;
 
         let config = format!(
             r#"## Nioca SSH certificate configuration
@@ -973,13 +1091,13 @@ async fn install_host_ssh(certs: &SshCertificateResponse) -> anyhow::Result<()>
     HostCertificate {}
 
         "#,
-            path_ca_pub, path_id, path_id_pub,
+            PATH_SSH_CA_PUB, path_id, path_id_pub,
         );
 
         // save new config
         let ssh_config = "/etc/ssh/sshd_config.d/10-nioca.conf";
         println!("Writing sshd certificate config to {}", ssh_config);
-        fs::write(&ssh_config, config.as_bytes()).await?;
+        write_atomic(ssh_config, config.as_bytes(), false).await?;
 
         // make sure that the *.d folder is included in the sshd_config
         let sshd_config_include = "Include /etc/ssh/sshd_config.d/*.conf";
@@ -994,7 +1112,7 @@ async fn install_host_ssh(certs: &SshCertificateResponse) -> anyhow::Result<()>
         }
         if !d_is_included {
             writeln!(sshd_config, "{}", sshd_config_include)?;
-            fs::write(&path_sshd_config, sshd_config).await?;
+            write_atomic(path_sshd_config, sshd_config.as_bytes(), false).await?;
         }
 
         println!("Restarting sshd to read the new config");
@@ -1034,19 +1152,20 @@ async fn install_known_host(certs: &SshCertificateResponse) -> anyhow::Result<()
 
     let path = format!("{}{}.ssh", home.display(), SEPARATOR);
     let path_known_hosts = format!("{}{}known_hosts", path, SEPARATOR);
-    if File::open(&path_known_hosts).await.is_err() {
+    let exists = File::open(&path_known_hosts).await.is_ok();
+    let mut known_hosts = if exists {
+        fs::read_to_string(&path_known_hosts).await?
+    } else {
         println!(
             "known_hosts file not found in {} - creating it now",
             path_known_hosts
         );
         fs::create_dir_all(&path).await?;
-        fs::write(&path_known_hosts, b"").await?;
-        set_perm_user_only(&path_known_hosts).await?;
-    }
+        String::new()
+    };
 
     let entry = format!("@cert-authority * {} nioca-ssh-ca", certs.user_ca_pub);
 
-    let mut known_hosts = fs::read_to_string(&path_known_hosts).await?;
     for line in known_hosts.lines() {
         if line == entry {
             // println!("CA certificate already exists in known_hosts");
@@ -1055,8 +1174,9 @@ async fn install_known_host(certs: &SshCertificateResponse) -> anyhow::Result<()
     }
 
     // If we get until here, the cert does not exist in the known_hosts - append it to the end
+    // a new known_hosts must only be readable by the current user
     writeln!(known_hosts, "{}", entry)?;
-    fs::write(&path_known_hosts, known_hosts).await?;
+    write_atomic(&path_known_hosts, known_hosts.as_bytes(), !exists).await?;
 
     println!("CA certificate has been added to {}", path_known_hosts);
 
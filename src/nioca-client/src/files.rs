@@ -0,0 +1,536 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use zeroize::Zeroizing;
+
+#[cfg(not(target_family = "unix"))]
+use std::process::Stdio;
+#[cfg(not(target_family = "unix"))]
+use tokio::process::Command;
+
+/// A file to save. The contents are wiped from memory on drop, since they may contain the key.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OutFile {
+    pub name: String,
+    pub contents: Zeroizing<Vec<u8>>,
+    pub perms: FilePerms,
+}
+
+/// The permissions of a saved file. `mode`, `uid` and `gid` are ignored on Windows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct FilePerms {
+    /// Secret files are only readable by the current user, unless a `mode` is given
+    pub secret: bool,
+    /// The unix file mode
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// How many old certificate versions are kept in the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Retention {
+    /// The maximum number of versions including the current one
+    pub keep: usize,
+    /// Versions which have been saved longer ago are pruned, even if `keep` is not reached
+    pub max_age: Option<Duration>,
+}
+
+/// Atomically replaces the file at `path` with `contents`.
+///
+/// The contents are written to a temp file in the same directory, which is synced to disk
+/// and then renamed over the target. Readers will always see either the old or the new file,
+/// even if we crash in between. Secret files are only ever readable by the current user,
+/// the permissions are set before any content is written. Other files keep the mode of the
+/// file they replace.
+pub(crate) async fn write_atomic(
+    path: impl AsRef<Path>,
+    contents: &[u8],
+    secret: bool,
+) -> anyhow::Result<()> {
+    let perms = FilePerms {
+        secret,
+        ..Default::default()
+    };
+    write_atomic_perms(path, contents, &perms).await
+}
+
+/// Like [write_atomic] with explicit permissions, which are applied before any content is
+/// written as well.
+pub(crate) async fn write_atomic_perms(
+    path: impl AsRef<Path>,
+    contents: &[u8],
+    perms: &FilePerms,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let (tmp, dir) = tmp_path(path)?;
+
+    let res = write_replace(&tmp, path, dir, contents, perms).await;
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    res.with_context(|| format!("Writing {}", path.display()))
+}
+
+/// Saves a new version of a certificate in a certbot-like layout inside `base`:
+///
+/// - `archive/<version>/` contains the files of each version, named after the `not_after`
+///   of the certificate
+/// - `live` is a symlink to the current version
+/// - `current` contains the name of the current version
+/// - each file exists in `base` as well as a symlink into `live`, which keeps the paths of
+///   the old flat layout working
+///
+/// Switching `live` to the new version is atomic, so readers never see a certificate with
+/// the key of another version. Old versions are pruned afterwards with the given `retention`.
+///
+/// Symlinks need special privileges on Windows, which is why `live` and the files in `base`
+/// are plain copies there.
+///
+/// Older versions of the client saved each version as `<not_after>/` directly inside `base`.
+/// These are moved into the archive on the first run, so they are pruned like all others.
+///
+/// Returns the path of the new version in the archive.
+pub(crate) async fn save_versioned(
+    base: &Path,
+    not_after: i64,
+    files: &[OutFile],
+    retention: &Retention,
+) -> anyhow::Result<PathBuf> {
+    let archive = base.join("archive");
+    if let Err(err) = migrate_legacy(base, &archive).await {
+        eprintln!(
+            "Error moving old certificates from {} into the archive: {}",
+            base.display(),
+            err
+        );
+    }
+
+    let version = next_version(&archive, not_after).await?;
+    let dir = archive.join(&version);
+    fs::create_dir_all(&dir).await?;
+
+    for file in files {
+        write_atomic_perms(dir.join(&file.name), &file.contents, &file.perms).await?;
+    }
+
+    let live = base.join("live");
+    #[cfg(target_family = "unix")]
+    {
+        replace_symlink(&Path::new("archive").join(&version), &live).await?;
+        for file in files {
+            replace_symlink(&Path::new("live").join(&file.name), &base.join(&file.name)).await?;
+        }
+    }
+    #[cfg(not(target_family = "unix"))]
+    {
+        fs::create_dir_all(&live).await?;
+        for file in files {
+            write_atomic_perms(live.join(&file.name), &file.contents, &file.perms).await?;
+            write_atomic_perms(base.join(&file.name), &file.contents, &file.perms).await?;
+        }
+    }
+
+    write_atomic(
+        base.join("current"),
+        format!("{}\n", version).as_bytes(),
+        false,
+    )
+    .await?;
+
+    // the new version is live already - a failed cleanup must not fail the renewal
+    if let Err(err) = prune(&archive, &version, retention).await {
+        eprintln!(
+            "Error pruning old certificates in {}: {}",
+            archive.display(),
+            err
+        );
+    }
+
+    Ok(dir)
+}
+
+/// Returns the name for a new version in the archive. Versions are named after the
+/// `not_after` of the certificate with a counter appended, if it already exists.
+async fn next_version(archive: &Path, not_after: i64) -> anyhow::Result<String> {
+    let mut version = not_after.to_string();
+    let mut n = 1;
+    while fs::try_exists(archive.join(&version)).await? {
+        version = format!("{}.{}", not_after, n);
+        n += 1;
+    }
+    Ok(version)
+}
+
+/// Parses a version name into something sortable. Returns `None` for unknown entries.
+fn parse_version(name: &str) -> Option<(i64, u32)> {
+    match name.split_once('.') {
+        Some((not_after, n)) => Some((not_after.parse().ok()?, n.parse().ok()?)),
+        None => Some((name.parse().ok()?, 0)),
+    }
+}
+
+/// Moves the version directories of the old flat layout from `base` into the `archive`.
+async fn migrate_legacy(base: &Path, archive: &Path) -> anyhow::Result<()> {
+    let mut entries = match fs::read_dir(base).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let not_after = match parse_version(&name) {
+            Some((not_after, _)) if entry.file_type().await?.is_dir() => not_after,
+            _ => continue,
+        };
+
+        fs::create_dir_all(archive).await?;
+        let version = next_version(archive, not_after).await?;
+        println!("Moving old certificate version {} into the archive", name);
+        fs::rename(entry.path(), archive.join(version)).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes all versions from the archive, which exceed the `retention`. The `current`
+/// version is always kept.
+async fn prune(archive: &Path, current: &str, retention: &Retention) -> anyhow::Result<()> {
+    let mut versions = Vec::new();
+    let mut entries = fs::read_dir(archive).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(order) = parse_version(&name) {
+            let modified = entry.metadata().await?.modified().ok();
+            versions.push((order, name, modified));
+        }
+    }
+    versions.sort_by_key(|(order, _, _)| std::cmp::Reverse(*order));
+
+    let now = SystemTime::now();
+    for (i, (_, name, modified)) in versions.into_iter().enumerate() {
+        if name == current {
+            continue;
+        }
+
+        let too_many = i >= retention.keep.max(1);
+        let too_old = match (retention.max_age, modified) {
+            (Some(max_age), Some(modified)) => now
+                .duration_since(modified)
+                .map(|age| age > max_age)
+                .unwrap_or(false),
+            _ => false,
+        };
+        if too_many || too_old {
+            println!("Pruning old certificate version {}", name);
+            fs::remove_dir_all(archive.join(&name)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Atomically points `link` to `target`.
+#[cfg(target_family = "unix")]
+async fn replace_symlink(target: &Path, link: &Path) -> anyhow::Result<()> {
+    if fs::read_link(link).await.ok().as_deref() == Some(target) {
+        return Ok(());
+    }
+
+    let (tmp, dir) = tmp_path(link)?;
+    let res = async {
+        fs::symlink(target, &tmp).await?;
+        fs::rename(&tmp, link).await?;
+        sync_dir(dir).await
+    }
+    .await;
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    res.with_context(|| format!("Linking {} to {}", link.display(), target.display()))
+}
+
+/// Returns a temp path next to `path` and the directory of both.
+fn tmp_path(path: &Path) -> anyhow::Result<(PathBuf, &Path)> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Invalid file path: {}", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    Ok((tmp, dir))
+}
+
+/// A rename is only durable after its directory has been synced.
+async fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    #[cfg(target_family = "unix")]
+    fs::File::open(dir).await?.sync_all().await?;
+    #[cfg(not(target_family = "unix"))]
+    let _ = dir;
+    Ok(())
+}
+
+async fn write_replace(
+    tmp: &Path,
+    path: &Path,
+    dir: &Path,
+    contents: &[u8],
+    perms: &FilePerms,
+) -> anyhow::Result<()> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    opts.mode(0o600);
+
+    // a leftover from a crashed run with the same pid
+    let _ = fs::remove_file(tmp).await;
+    let mut file = opts.open(tmp).await?;
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        // a replaced file keeps its mode and owner, unless they are given explicitly
+        let existing = fs::metadata(path).await.ok();
+        let mode = match (perms.mode, &existing) {
+            (Some(mode), _) => mode,
+            (None, _) if perms.secret => 0o600,
+            (None, Some(meta)) => meta.permissions().mode() & 0o7777,
+            (None, None) => 0o644,
+        };
+        let created = file.metadata().await?;
+        let uid = perms
+            .uid
+            .or_else(|| existing.as_ref().map(|meta| meta.uid()))
+            .unwrap_or(created.uid());
+        let gid = perms
+            .gid
+            .or_else(|| existing.as_ref().map(|meta| meta.gid()))
+            .unwrap_or(created.gid());
+        if uid != created.uid() || gid != created.gid() {
+            std::os::unix::fs::chown(tmp, Some(uid), Some(gid))?;
+        }
+        // set explicitly, since the umask may have changed the mode on creation
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await?;
+    }
+    #[cfg(not(target_family = "unix"))]
+    if perms.secret {
+        set_perm_user_only(&tmp.to_string_lossy()).await?;
+    }
+
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(tmp, path).await?;
+    sync_dir(dir).await
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn set_perm_user_only(path: &str) -> anyhow::Result<()> {
+    // lets get our username first
+    let out = Command::new("powershell.exe")
+        .arg("-c")
+        .arg("$env:UserName")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+    let username = String::from_utf8_lossy(&out.stdout).trim().to_string();
+
+    println!("Setting access only to {} for {}", username, path);
+
+    // remove inheritance
+    if !Command::new("icacls.exe")
+        .arg(path)
+        .arg("/inheritance:r")
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait()
+        .await?
+        .success()
+    {
+        eprintln!("Error removing file inheritance for {}", path);
+    }
+
+    // set ownership
+    if !Command::new("icacls.exe")
+        .arg(path)
+        .arg("/grant")
+        .arg(format!("{}:F", username))
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait()
+        .await?
+        .success()
+    {
+        eprintln!("Error granting user full access to {}", path);
+    }
+    if !Command::new("takeown.exe")
+        .arg("/F")
+        .arg(path)
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait()
+        .await?
+        .success()
+    {
+        eprintln!("Error taking ownership for {}", path);
+    }
+    if !Command::new("icacls.exe")
+        .arg(path)
+        .arg("/grant:r")
+        .arg(format!("{}:F", username))
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait()
+        .await?
+        .success()
+    {
+        eprintln!("Error granting 'read' to {}", path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("nioca-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("key.pem");
+
+        write_atomic(&path, b"old", true).await.unwrap();
+        write_atomic(&path, b"new", true).await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"new");
+
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // no temp files must be left behind
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec!["key.pem"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    /// Changing the owner of a file requires root.
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_write_atomic_keeps_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("nioca-owner-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("sshd_config");
+
+        write_atomic(&path, b"old", false).await.unwrap();
+        std::os::unix::fs::chown(&path, Some(1234), Some(4321)).unwrap();
+        write_atomic(&path, b"new", false).await.unwrap();
+        let meta = fs::metadata(&path).await.unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 4321));
+
+        // an explicit owner still wins
+        let perms = FilePerms {
+            uid: Some(0),
+            ..Default::default()
+        };
+        write_atomic_perms(&path, b"newer", &perms).await.unwrap();
+        let meta = fs::metadata(&path).await.unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (0, 4321));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_versioned() {
+        let base = std::env::temp_dir().join(format!("nioca-versions-{}", std::process::id()));
+        let retention = Retention {
+            keep: 2,
+            max_age: None,
+        };
+
+        for (not_after, contents) in [(100, "v1"), (200, "v2"), (200, "v3")] {
+            let files = vec![OutFile {
+                name: "cert.pem".to_string(),
+                contents: contents.as_bytes().to_vec().into(),
+                perms: FilePerms::default(),
+            }];
+            save_versioned(&base, not_after, &files, &retention)
+                .await
+                .unwrap();
+        }
+
+        for path in ["cert.pem", "live/cert.pem", "archive/200.1/cert.pem"] {
+            assert_eq!(fs::read(base.join(path)).await.unwrap(), b"v3");
+        }
+        assert_eq!(
+            fs::read_to_string(base.join("current")).await.unwrap(),
+            "200.1\n"
+        );
+        assert!(fs::try_exists(base.join("archive/200")).await.unwrap());
+        assert!(!fs::try_exists(base.join("archive/100")).await.unwrap());
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_versioned_migrates_legacy_layout() {
+        let base = std::env::temp_dir().join(format!("nioca-legacy-{}", std::process::id()));
+        for (dir, contents) in [("100", "v1"), ("150", "v2")] {
+            fs::create_dir_all(base.join(dir)).await.unwrap();
+            fs::write(base.join(dir).join("cert.pem"), contents)
+                .await
+                .unwrap();
+        }
+        fs::write(base.join("cert.pem"), "v2").await.unwrap();
+
+        let retention = Retention {
+            keep: 2,
+            max_age: None,
+        };
+        let files = vec![OutFile {
+            name: "cert.pem".to_string(),
+            contents: b"v3".to_vec().into(),
+            perms: FilePerms::default(),
+        }];
+        save_versioned(&base, 200, &files, &retention)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(base.join("cert.pem")).await.unwrap(), b"v3");
+        assert_eq!(
+            fs::read(base.join("archive/150/cert.pem")).await.unwrap(),
+            b"v2"
+        );
+        for path in ["100", "150", "archive/100"] {
+            assert!(!fs::try_exists(base.join(path)).await.unwrap(), "{}", path);
+        }
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time;
+
+/// A freshly saved certificate, which is described to the hooks with environment variables:
+///
+/// - `NIOCA_CERT_TYPE`: `x509` or `ssh`
+/// - `NIOCA_CERT_DIR`: the directory with the current files
+/// - `NIOCA_CERT_FILES`: the space separated paths of all saved files
+/// - `NIOCA_NOT_AFTER`: the end of the validity as a unix timestamp in seconds
+/// - `NIOCA_FINGERPRINT`: the fingerprint of the certificate
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Renewed {
+    pub typ: &'static str,
+    pub dir: PathBuf,
+    pub files: Vec<PathBuf>,
+    pub not_after: i64,
+    pub fingerprint: String,
+}
+
+impl Renewed {
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let files = self
+            .files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        vec![
+            ("NIOCA_CERT_TYPE", self.typ.to_string()),
+            ("NIOCA_CERT_DIR", self.dir.display().to_string()),
+            ("NIOCA_CERT_FILES", files),
+            ("NIOCA_NOT_AFTER", self.not_after.to_string()),
+            ("NIOCA_FINGERPRINT", self.fingerprint.clone()),
+        ]
+    }
+}
+
+/// The hooks, which are executed after each successfully saved certificate.
+///
+/// They are configured per certificate type with the `<PREFIX>_HOOK_*` values from the config,
+/// where the prefix is `NIOCA_X509` or `NIOCA_SSH`:
+///
+/// - `_HOOK_COMMAND`: a shell command
+/// - `_HOOK_RELOAD`: comma separated systemd units for `systemctl reload`
+/// - `_HOOK_SIGNAL`: comma separated `<pidfile>:<signal>` pairs like `/run/nginx.pid:HUP`
+/// - `_HOOK_TIMEOUT`: the timeout in seconds for each hook (default: 30)
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Hooks {
+    pub command: Option<String>,
+    pub reload: Vec<String>,
+    pub signal: Vec<(String, String)>,
+    pub timeout: Duration,
+}
+
+impl Hooks {
+    pub fn from_env(prefix: &str) -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            env::var(format!("{}_HOOK_{}", prefix, name))
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let list = |value: Option<String>| {
+            value
+                .unwrap_or_default()
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let signal = list(var("SIGNAL"))
+            .into_iter()
+            .map(|item| match item.rsplit_once(':') {
+                Some((pidfile, signal)) => Ok((pidfile.to_string(), signal.to_string())),
+                None => Err(anyhow::Error::msg(format!(
+                    "{}_HOOK_SIGNAL must be in the format <pidfile>:<signal>, got: {}",
+                    prefix, item
+                ))),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let timeout = match var("TIMEOUT") {
+            Some(secs) => secs.parse::<u64>().map_err(|_| {
+                anyhow::Error::msg(format!("Cannot parse {}_HOOK_TIMEOUT to u64", prefix))
+            })?,
+            None => 30,
+        };
+
+        Ok(Self {
+            command: var("COMMAND"),
+            reload: list(var("RELOAD")),
+            signal,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+
+    /// Runs all hooks one after another. Failures are only reported, since a certificate which
+    /// has been saved already must never stop the renewal.
+    ///
+    /// Returns the number of failed hooks.
+    pub async fn run(&self, renewed: &Renewed) -> usize {
+        let mut failed = 0;
+
+        if let Some(command) = &self.command {
+            let cmd = shell(command);
+            if !self.exec(cmd, command, renewed).await {
+                failed += 1;
+            }
+        }
+
+        for unit in &self.reload {
+            let mut cmd = Command::new("/usr/bin/systemctl");
+            cmd.arg("reload").arg(unit);
+            if !self
+                .exec(cmd, &format!("systemctl reload {}", unit), renewed)
+                .await
+            {
+                failed += 1;
+            }
+        }
+
+        for (pidfile, signal) in &self.signal {
+            let pid = match tokio::fs::read_to_string(pidfile).await {
+                Ok(pid) => pid.trim().to_string(),
+                Err(err) => {
+                    eprintln!("Hook error: cannot read pidfile {}: {}", pidfile, err);
+                    failed += 1;
+                    continue;
+                }
+            };
+            if pid.parse::<u32>().is_err() {
+                eprintln!("Hook error: invalid PID in {}: {}", pidfile, pid);
+                failed += 1;
+                continue;
+            }
+
+            let mut cmd = Command::new("kill");
+            cmd.arg("-s").arg(signal).arg(&pid);
+            let name = format!("kill -s {} {}", signal, pid);
+            if !self.exec(cmd, &name, renewed).await {
+                failed += 1;
+            }
+        }
+
+        failed
+    }
+
+    async fn exec(&self, mut cmd: Command, name: &str, renewed: &Renewed) -> bool {
+        println!("Running hook: {}", name);
+
+        let child = cmd
+            .envs(renewed.env())
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("Hook error: cannot execute '{}': {}", name, err);
+                return false;
+            }
+        };
+
+        match time::timeout(self.timeout, child.wait()).await {
+            Ok(Ok(status)) if status.success() => true,
+            Ok(Ok(status)) => {
+                eprintln!("Hook error: '{}' failed with {}", name, status);
+                false
+            }
+            Ok(Err(err)) => {
+                eprintln!("Hook error: waiting for '{}': {}", name, err);
+                false
+            }
+            Err(_) => {
+                eprintln!(
+                    "Hook error: '{}' did not finish within {} seconds - killing it",
+                    name,
+                    self.timeout.as_secs()
+                );
+                let _ = child.kill().await;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(target_family = "unix"))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd.exe");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_hooks() {
+        let renewed = Renewed {
+            typ: "x509",
+            dir: PathBuf::from("/tmp/certs/x509/live"),
+            files: vec![PathBuf::from("/tmp/certs/x509/live/cert.pem")],
+            not_after: 1700000000,
+            fingerprint: "sha256:abc".to_string(),
+        };
+
+        let hooks = Hooks {
+            command: Some(
+                r#"test "$NIOCA_NOT_AFTER" = 1700000000 && test "$NIOCA_CERT_TYPE" = x509"#
+                    .to_string(),
+            ),
+            reload: Vec::new(),
+            signal: vec![("/does/not/exist.pid".to_string(), "HUP".to_string())],
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(hooks.run(&renewed).await, 1);
+
+        let hooks = Hooks {
+            command: Some("sleep 5".to_string()),
+            reload: Vec::new(),
+            signal: Vec::new(),
+            timeout: Duration::from_millis(100),
+        };
+        assert_eq!(hooks.run(&renewed).await, 1);
+    }
+}
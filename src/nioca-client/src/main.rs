@@ -1,4 +1,7 @@
 mod cli;
+mod files;
+mod hooks;
+mod output;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -0,0 +1,373 @@
+use crate::files::{FilePerms, OutFile};
+use anyhow::Context;
+use nioca_common::format::X509Bundle;
+use nioca_common::ssh::SshCertificateResponse;
+use nioca_common::tls::{certs_to_pem, der_to_pem};
+use nioca_common::x509::CertX509Response;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use tokio::fs;
+use zeroize::Zeroizing;
+
+/// File names, which are used by the live / archive layout of the X509 output directory.
+const RESERVED_NAMES: [&str; 3] = ["archive", "current", "live"];
+
+/// The contents of an X509 output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum X509Contents {
+    /// The leaf certificate
+    Cert,
+    /// The rest of the chain without the leaf
+    Chain,
+    /// The leaf followed by the rest of the chain
+    Fullchain,
+    /// The private key as delivered by Nioca as PEM, or PKCS8 as DER
+    Key,
+    /// The private key in PKCS8
+    Pkcs8Key,
+    /// The full chain followed by the private key in a single PEM file, like haproxy expects it
+    CertKey,
+    /// A PKCS12 keystore with the full chain and the key, encrypted with
+    /// NIOCA_X509_PKCS12_PASSWORD
+    Pkcs12,
+}
+
+/// The contents of an SSH output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SshContents {
+    /// The private key in OpenSSH format
+    Key,
+    /// The SSH certificate
+    Cert,
+    /// The public key of the User CA
+    CaPub,
+}
+
+/// The encoding of an X509 output file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OutputFormat {
+    #[default]
+    Pem,
+    Der,
+}
+
+pub(crate) trait Contents: DeserializeOwned + Copy {
+    fn is_secret(&self) -> bool;
+
+    /// Returns an error, if the contents cannot be written in the given format.
+    fn check_format(&self, format: Option<OutputFormat>) -> Result<(), String>;
+}
+
+impl Contents for X509Contents {
+    fn is_secret(&self) -> bool {
+        matches!(
+            self,
+            Self::Key | Self::Pkcs8Key | Self::CertKey | Self::Pkcs12
+        )
+    }
+
+    fn check_format(&self, format: Option<OutputFormat>) -> Result<(), String> {
+        match (self, format) {
+            (Self::Chain | Self::Fullchain | Self::CertKey, Some(OutputFormat::Der)) => Err(
+                "chain, fullchain and cert_key contain multiple blocks and can only be PEM"
+                    .to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Contents for SshContents {
+    fn is_secret(&self) -> bool {
+        *self == Self::Key
+    }
+
+    fn check_format(&self, format: Option<OutputFormat>) -> Result<(), String> {
+        match format {
+            Some(_) => Err("format is only supported for X509 certificates".to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A single file of the declarative output spec, which is a JSON array of these.
+///
+/// ```json
+/// [
+///   { "filename": "fullchain.pem", "contents": "fullchain" },
+///   { "filename": "haproxy.pem", "contents": "cert_key", "mode": "0640", "group": "haproxy" },
+///   { "filename": "cert.der", "contents": "cert", "format": "der" }
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutputSpec<C> {
+    pub filename: String,
+    pub contents: C,
+    /// pem or der, only for X509 certificates and ignored for PKCS12 (default: pem)
+    pub format: Option<OutputFormat>,
+    /// The unix file mode in octal like "0640" (default: 0600 for contents with the key,
+    /// otherwise the mode of the replaced file or 0644)
+    pub mode: Option<String>,
+    /// The name or uid of the owner
+    pub owner: Option<String>,
+    /// The name or gid of the group
+    pub group: Option<String>,
+}
+
+/// A validated [OutputSpec] with resolved permissions.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Output<C> {
+    pub filename: String,
+    pub contents: C,
+    pub format: OutputFormat,
+    pub perms: FilePerms,
+}
+
+/// Loads the output spec from the JSON file at the path in `<prefix>_OUTPUTS`, if it is set.
+pub(crate) async fn load<C: Contents>(prefix: &str) -> anyhow::Result<Option<Vec<Output<C>>>> {
+    let var = format!("{}_OUTPUTS", prefix);
+    let path = match env::var(&var) {
+        Ok(path) if !path.trim().is_empty() => path.trim().to_string(),
+        _ => return Ok(None),
+    };
+
+    let json = fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Reading the {} output spec from {}", var, path))?;
+    let outputs = parse(&json).with_context(|| format!("Invalid output spec in {}", path))?;
+    Ok(Some(outputs))
+}
+
+fn parse<C: Contents>(json: &str) -> anyhow::Result<Vec<Output<C>>> {
+    let specs: Vec<OutputSpec<C>> = serde_json::from_str(json)?;
+    if specs.is_empty() {
+        return Err(anyhow::Error::msg(
+            "The output spec must contain at least one file",
+        ));
+    }
+
+    let mut names = HashSet::new();
+    let mut outputs = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let name = &spec.filename;
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.contains(['/', '\\'])
+            || RESERVED_NAMES.contains(&name.as_str())
+        {
+            return Err(anyhow::Error::msg(format!("Invalid filename: '{}'", name)));
+        }
+        if !names.insert(name.clone()) {
+            return Err(anyhow::Error::msg(format!(
+                "Duplicate filename: '{}'",
+                name
+            )));
+        }
+        spec.contents
+            .check_format(spec.format)
+            .map_err(|err| anyhow::Error::msg(format!("{}: {}", name, err)))?;
+
+        let mode = match &spec.mode {
+            Some(mode) => {
+                let parsed = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777);
+                Some(parsed.ok_or_else(|| {
+                    anyhow::Error::msg(format!("{}: invalid mode '{}'", name, mode))
+                })?)
+            }
+            None => None,
+        };
+
+        let perms = FilePerms {
+            secret: spec.contents.is_secret(),
+            mode,
+            uid: spec.owner.as_deref().map(lookup_user).transpose()?,
+            gid: spec.group.as_deref().map(lookup_group).transpose()?,
+        };
+        outputs.push(Output {
+            filename: spec.filename,
+            contents: spec.contents,
+            format: spec.format.unwrap_or_default(),
+            perms,
+        });
+    }
+
+    Ok(outputs)
+}
+
+/// Resolves a user name through NSS. Numeric ids are used as they are.
+#[cfg(target_family = "unix")]
+fn lookup_user(name: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    nix::unistd::User::from_name(name)
+        .with_context(|| format!("Looking up user '{}'", name))?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| anyhow::Error::msg(format!("Unknown user '{}'", name)))
+}
+
+/// Resolves a group name through NSS. Numeric ids are used as they are.
+#[cfg(target_family = "unix")]
+fn lookup_group(name: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    nix::unistd::Group::from_name(name)
+        .with_context(|| format!("Looking up group '{}'", name))?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| anyhow::Error::msg(format!("Unknown group '{}'", name)))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn lookup_user(_name: &str) -> anyhow::Result<u32> {
+    Err(anyhow::Error::msg(
+        "owner and group are only supported on unix",
+    ))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn lookup_group(name: &str) -> anyhow::Result<u32> {
+    lookup_user(name)
+}
+
+/// The files, which are saved for SSH certificates without an output spec.
+pub(crate) fn default_ssh() -> Vec<Output<SshContents>> {
+    [
+        ("id_nioca", SshContents::Key),
+        ("id_nioca.pub", SshContents::Cert),
+        ("id_nioca_ca.pub", SshContents::CaPub),
+    ]
+    .into_iter()
+    .map(|(filename, contents)| Output {
+        filename: filename.to_string(),
+        contents,
+        format: OutputFormat::Pem,
+        perms: FilePerms {
+            secret: contents.is_secret(),
+            ..Default::default()
+        },
+    })
+    .collect()
+}
+
+/// Builds the X509 files for the given outputs from a PEM response.
+pub(crate) fn render_x509(
+    outputs: &[Output<X509Contents>],
+    certs: &CertX509Response,
+    pkcs12_password: Option<&str>,
+) -> anyhow::Result<Vec<OutFile>> {
+    let bundle = X509Bundle::from_response(certs, pkcs12_password)?;
+
+    let mut files = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let contents = match (output.contents, output.format) {
+            (X509Contents::Cert, OutputFormat::Pem) => {
+                der_to_pem("CERTIFICATE", &bundle.leaf().0).into_bytes()
+            }
+            (X509Contents::Cert, OutputFormat::Der) => bundle.leaf().0.clone(),
+            (X509Contents::Chain, _) => certs_to_pem(&bundle.chain[1..]).into_bytes(),
+            (X509Contents::Fullchain, _) => certs_to_pem(&bundle.chain).into_bytes(),
+            (X509Contents::Key, OutputFormat::Pem) => certs.key.expose_secret().as_bytes().to_vec(),
+            (X509Contents::Pkcs8Key, OutputFormat::Pem) => {
+                der_to_pem("PRIVATE KEY", &bundle.key.0).into_bytes()
+            }
+            (X509Contents::Key | X509Contents::Pkcs8Key, OutputFormat::Der) => bundle.key.0.clone(),
+            (X509Contents::CertKey, _) => {
+                let mut pem = certs_to_pem(&bundle.chain).into_bytes();
+                pem.extend_from_slice(certs.key.expose_secret().as_bytes());
+                pem
+            }
+            (X509Contents::Pkcs12, _) => bundle.to_pkcs12(pkcs12_password.unwrap_or_default())?,
+        };
+
+        files.push(OutFile {
+            name: output.filename.clone(),
+            contents: Zeroizing::new(contents),
+            perms: output.perms.clone(),
+        });
+    }
+
+    Ok(files)
+}
+
+/// Builds the SSH files for the given outputs.
+pub(crate) fn render_ssh(
+    outputs: &[Output<SshContents>],
+    certs: &SshCertificateResponse,
+) -> Vec<OutFile> {
+    outputs
+        .iter()
+        .map(|output| {
+            let contents = match output.contents {
+                SshContents::Key => certs.host_key_pair.id.expose_secret(),
+                SshContents::Cert => &certs.host_key_pair.id_pub,
+                SshContents::CaPub => &certs.user_ca_pub,
+            };
+            OutFile {
+                name: output.filename.clone(),
+                contents: Zeroizing::new(contents.as_bytes().to_vec()),
+                perms: output.perms.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_output_spec() {
+        let outputs = parse::<X509Contents>(
+            r#"[
+                { "filename": "fullchain.pem", "contents": "fullchain" },
+                { "filename": "haproxy.pem", "contents": "cert_key", "mode": "0640", "owner": "root", "group": "0" },
+                { "filename": "key.der", "contents": "pkcs8_key", "format": "der" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].perms, FilePerms::default());
+        assert_eq!(outputs[2].format, OutputFormat::Der);
+        assert!(outputs[2].perms.secret);
+
+        #[cfg(target_family = "unix")]
+        assert_eq!(
+            outputs[1].perms,
+            FilePerms {
+                secret: true,
+                mode: Some(0o640),
+                uid: Some(0),
+                gid: Some(0),
+            }
+        );
+
+        for invalid in [
+            r#"[]"#,
+            r#"[{ "filename": "chain.der", "contents": "chain", "format": "der" }]"#,
+            r#"[{ "filename": "../key.pem", "contents": "key" }]"#,
+            r#"[{ "filename": "live", "contents": "cert" }]"#,
+            r#"[{ "filename": "key.pem", "contents": "key", "mode": "0999" }]"#,
+            r#"[{ "filename": "a.pem", "contents": "cert" }, { "filename": "a.pem", "contents": "key" }]"#,
+            r#"[{ "filename": "a.pem", "contents": "unknown" }]"#,
+            r#"[{ "filename": "a.pem", "contents": "cert", "owner": "no-such-nioca-user" }]"#,
+        ] {
+            assert!(parse::<X509Contents>(invalid).is_err(), "{}", invalid);
+        }
+
+        assert!(parse::<SshContents>(
+            r#"[{ "filename": "id", "contents": "key", "format": "pem" }]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,559 @@
+use crate::pinning::{normalize_fingerprint, verify_root_pin};
+use crate::renewal::RenewalPolicy;
+#[cfg(feature = "ssh")]
+use crate::ssh::SshCertType;
+use crate::tls::ClientAuth;
+use crate::{NiocaError, Secret};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::debug;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct NiocaConfig {
+    pub url: String,
+    pub url_ssh: Option<String>,
+    pub url_x509: Option<String>,
+    pub root_cert: Option<reqwest::Certificate>,
+    pub root_pem: Option<String>,
+    pub api_key_ssh: Option<Secret>,
+    pub api_key_x509: Option<Secret>,
+    pub connect_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+    /// If set, every connection to Nioca must chain up to a root with this fingerprint
+    pub pin_root_fingerprint: Option<String>,
+    /// If set, every connection to Nioca must present an intermediate with this fingerprint
+    pub pin_intermediate_fingerprint: Option<String>,
+    /// Controls the renewal and retry intervals of all renewal loops
+    pub renewal: RenewalPolicy,
+    /// How far the start of a certificate's validity may be in the future, because the local
+    /// clock is slightly behind the one of Nioca
+    pub clock_skew: Duration,
+    /// Client certificate authentication for servers built by the framework integrations
+    pub client_auth: ClientAuth,
+    /// The password for PKCS12 certificates, either delivered by Nioca or written by the CLI
+    pub pkcs12_password: Option<Secret>,
+    /// SHA256 fingerprints of the CA keys SSH certificates must be signed with
+    #[cfg(feature = "ssh")]
+    pub ssh_ca_fingerprints: Vec<String>,
+    /// Trust the `user_ca_pub` Nioca sends along with each SSH certificate, if no
+    /// `ssh_ca_fingerprints` are given
+    #[cfg(feature = "ssh")]
+    pub ssh_trust_response_ca: bool,
+    /// If set, fetched SSH certificates must be of this type
+    #[cfg(feature = "ssh")]
+    pub ssh_cert_type: Option<SshCertType>,
+}
+
+impl NiocaConfig {
+    /// Returns a builder for a `NiocaConfig` which does not touch the process environment.
+    pub fn builder() -> NiocaConfigBuilder {
+        NiocaConfigBuilder::default()
+    }
+
+    /// Builds the config from the environment and panics if it is invalid.
+    ///
+    /// Prefer [NiocaConfig::try_from_env] or [NiocaConfig::builder] inside libraries.
+    pub async fn from_env() -> Self {
+        Self::try_from_env()
+            .await
+            .expect("Building NiocaConfig from env")
+    }
+
+    /// Builds the config from the environment, reading a `.env` file if it exists.
+    pub async fn try_from_env() -> Result<Self, NiocaError> {
+        dotenvy::dotenv().ok();
+
+        let builder = NiocaConfigBuilder::from_lookup(|name| env::var(name).ok())?;
+
+        // if we do not have a configured env var, try to find an existing root PEM in the
+        // nioca config dir
+        #[cfg(feature = "cli")]
+        let builder = match (&builder.root_pem, home::home_dir()) {
+            (None, Some(path)) => {
+                let try_root_pem_path = format!("{}/.nioca/root.pem", path.display());
+                match tokio::fs::read_to_string(&try_root_pem_path).await {
+                    Ok(root_pem) => builder.root_pem(root_pem),
+                    Err(_) => builder,
+                }
+            }
+            _ => builder,
+        };
+
+        builder.build()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RootPemSource {
+    Pem(String),
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// Builder for a [NiocaConfig].
+///
+/// Only the `url` is mandatory. Everything else depends on the certificate types you want
+/// to fetch.
+#[derive(Debug, Clone, Default)]
+pub struct NiocaConfigBuilder {
+    url: Option<String>,
+    client_id_ssh: Option<String>,
+    api_key_ssh: Option<Secret>,
+    client_id_x509: Option<String>,
+    api_key_x509: Option<Secret>,
+    root_pem: Option<RootPemSource>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    pin_root_fingerprint: Option<String>,
+    pin_intermediate_fingerprint: Option<String>,
+    renewal: Option<RenewalPolicy>,
+    clock_skew: Option<Duration>,
+    client_auth: ClientAuth,
+    pkcs12_password: Option<Secret>,
+    #[cfg(feature = "ssh")]
+    ssh_ca_fingerprints: Vec<String>,
+    #[cfg(feature = "ssh")]
+    ssh_trust_response_ca: bool,
+    #[cfg(feature = "ssh")]
+    ssh_cert_type: Option<SshCertType>,
+}
+
+impl NiocaConfigBuilder {
+    /// Parses all config values apart from the root PEM file in the home dir with the given
+    /// lookup, which is `std::env::var` outside of tests.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, NiocaError> {
+        let url = lookup("NIOCA_URL")
+            .ok_or_else(|| NiocaError::Config("NIOCA_URL is not set".to_string()))?;
+        let mut builder = NiocaConfig::builder().url(url);
+
+        // the example .env contains these without values
+        let non_empty = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        if let Some(id) = non_empty("NIOCA_X509_CLIENT_ID") {
+            builder = builder.client_id_x509(id);
+        }
+        if let Some(key) = non_empty("NIOCA_X509_API_KEY") {
+            builder = builder.api_key_x509(key);
+        }
+        if let Some(id) = non_empty("NIOCA_SSH_CLIENT_ID") {
+            builder = builder.client_id_ssh(id);
+        }
+        if let Some(key) = non_empty("NIOCA_SSH_API_KEY") {
+            builder = builder.api_key_ssh(key);
+        }
+        if let Some(fingerprint) = lookup("NIOCA_ROOT_FINGERPRINT") {
+            builder = builder.pin_root_fingerprint(fingerprint);
+        }
+        if let Some(fingerprint) = lookup("NIOCA_INTERMEDIATE_FINGERPRINT") {
+            builder = builder.pin_intermediate_fingerprint(fingerprint);
+        }
+        if let Some(client_auth) = lookup("NIOCA_CLIENT_AUTH") {
+            builder = builder.client_auth(client_auth.parse()?);
+        }
+        if let Some(password) = lookup("NIOCA_X509_PKCS12_PASSWORD") {
+            builder = builder.pkcs12_password(password);
+        }
+        #[cfg(feature = "ssh")]
+        if let Some(fingerprints) = lookup("NIOCA_SSH_CA_FINGERPRINTS") {
+            for fingerprint in fingerprints.split(',').filter(|fp| !fp.trim().is_empty()) {
+                builder = builder.ssh_ca_fingerprint(fingerprint.trim());
+            }
+        }
+        #[cfg(feature = "ssh")]
+        if let Some(typ) = lookup("NIOCA_SSH_CERT_TYPE") {
+            builder = builder.ssh_cert_type(typ.parse()?);
+        }
+        #[cfg(feature = "ssh")]
+        if let Some(trust) = lookup("NIOCA_SSH_TRUST_RESPONSE_CA") {
+            let trust = trust.trim().parse::<bool>().map_err(|_| {
+                NiocaError::Config("Cannot parse NIOCA_SSH_TRUST_RESPONSE_CA to bool".to_string())
+            })?;
+            builder = builder.ssh_trust_response_ca(trust);
+        }
+        if let Some(secs) = lookup("NIOCA_CLOCK_SKEW") {
+            let secs = secs.trim().parse::<u64>().map_err(|_| {
+                NiocaError::Config("Cannot parse NIOCA_CLOCK_SKEW to u64".to_string())
+            })?;
+            builder = builder.clock_skew(Duration::from_secs(secs));
+        }
+        if let Some(secs) = lookup("ERROR_TIMEOUT") {
+            let secs = secs
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| NiocaError::Config("Cannot parse ERROR_TIMEOUT to u64".to_string()))?;
+            builder = builder.renewal_policy(RenewalPolicy {
+                backoff_initial: Duration::from_secs(secs),
+                ..Default::default()
+            });
+        }
+
+        if let Some(root_pem) = lookup("NIOCA_ROOT_PEM") {
+            builder = builder.root_pem(root_pem);
+        }
+
+        Ok(builder)
+    }
+
+    /// The base URL of the Nioca server, for instance `https://ca.local.dev:8443`
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn client_id_ssh(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id_ssh = Some(client_id.into());
+        self
+    }
+
+    pub fn api_key_ssh(mut self, api_key: impl Into<Secret>) -> Self {
+        self.api_key_ssh = Some(api_key.into());
+        self
+    }
+
+    pub fn client_id_x509(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id_x509 = Some(client_id.into());
+        self
+    }
+
+    pub fn api_key_x509(mut self, api_key: impl Into<Secret>) -> Self {
+        self.api_key_x509 = Some(api_key.into());
+        self
+    }
+
+    /// The Nioca root certificate in PEM format
+    pub fn root_pem(mut self, pem: impl Into<String>) -> Self {
+        self.root_pem = Some(RootPemSource::Pem(pem.into()));
+        self
+    }
+
+    /// A path to the Nioca root certificate in PEM format, which will be read during `build()`
+    pub fn root_pem_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.root_pem = Some(RootPemSource::Path(path.into()));
+        self
+    }
+
+    /// The Nioca root certificate in PEM format as raw bytes
+    pub fn root_pem_bytes(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.root_pem = Some(RootPemSource::Bytes(bytes.into()));
+        self
+    }
+
+    /// The connect timeout for all requests to Nioca (default: 10 seconds)
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// The total timeout for a single request to Nioca (default: none)
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Pins the SHA256 fingerprint of the Nioca root certificate.
+    ///
+    /// The root PEM is checked against it during `build()` and every connection to Nioca must
+    /// chain up to exactly this root. Requires a root PEM.
+    pub fn pin_root_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.pin_root_fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Pins the SHA256 fingerprint of the DER encoded intermediate certificate Nioca serves.
+    ///
+    /// Every connection to Nioca is rejected if the presented chain does not contain it.
+    /// Requires a root PEM.
+    pub fn pin_intermediate_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.pin_intermediate_fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Sets the renewal schedule (default: `RenewalPolicy::default()`)
+    pub fn renewal_policy(mut self, policy: RenewalPolicy) -> Self {
+        self.renewal = Some(policy);
+        self
+    }
+
+    /// Accepts certificates whose validity starts up to `skew` in the future, which happens
+    /// when the local clock is slightly behind the one of Nioca (default: 60 seconds)
+    pub fn clock_skew(mut self, skew: Duration) -> Self {
+        self.clock_skew = Some(skew);
+        self
+    }
+
+    /// Enables mutual TLS for servers built by the framework integrations. Connecting clients
+    /// are verified against the Nioca root, which means this requires a root PEM.
+    /// (default: `ClientAuth::None`)
+    pub fn client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// The password to decrypt PKCS12 certificates from Nioca and to encrypt PKCS12 output
+    /// (default: empty)
+    pub fn pkcs12_password(mut self, password: impl Into<Secret>) -> Self {
+        self.pkcs12_password = Some(password.into());
+        self
+    }
+
+    /// Adds a SHA256 fingerprint of a CA key, which may sign SSH certificates, in the OpenSSH
+    /// `SHA256:<base64>` format.
+    #[cfg(feature = "ssh")]
+    pub fn ssh_ca_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.ssh_ca_fingerprints.push(fingerprint.into());
+        self
+    }
+
+    /// Trusts the `user_ca_pub` Nioca sends along with each SSH certificate, if no CA
+    /// fingerprints are given. The certificate is then only checked against the response it
+    /// came with. (default: false)
+    #[cfg(feature = "ssh")]
+    pub fn ssh_trust_response_ca(mut self, trust: bool) -> Self {
+        self.ssh_trust_response_ca = trust;
+        self
+    }
+
+    /// Rejects all SSH certificates which are not of the given type (default: any type)
+    #[cfg(feature = "ssh")]
+    pub fn ssh_cert_type(mut self, typ: SshCertType) -> Self {
+        self.ssh_cert_type = Some(typ);
+        self
+    }
+
+    pub fn build(self) -> Result<NiocaConfig, NiocaError> {
+        let mut url = self
+            .url
+            .ok_or_else(|| NiocaError::Config("The Nioca URL is not set".to_string()))?;
+        while url.ends_with('/') {
+            let _ = url.split_off(url.len() - 1);
+        }
+        if url.is_empty() {
+            return Err(NiocaError::Config("The Nioca URL is empty".to_string()));
+        }
+        if !url.starts_with("https://") {
+            return Err(NiocaError::Config(format!(
+                "The Nioca URL must start with https://, got: {}",
+                url
+            )));
+        }
+        for (name, key) in [("SSH", &self.api_key_ssh), ("X509", &self.api_key_x509)] {
+            if matches!(key, Some(key) if key.expose_secret().trim().is_empty()) {
+                return Err(NiocaError::Config(format!("The {} API key is empty", name)));
+            }
+        }
+
+        let url_x509 = self
+            .client_id_x509
+            .map(|id| format!("{}/api/clients/x509/{}/cert", url, id));
+        let url_ssh = self
+            .client_id_ssh
+            .map(|id| format!("{}/api/clients/ssh/{}/cert", url, id));
+
+        let root_pem = match self.root_pem {
+            None => None,
+            Some(RootPemSource::Pem(pem)) => Some(pem),
+            Some(RootPemSource::Path(path)) => {
+                let pem = std::fs::read_to_string(&path).map_err(|err| {
+                    NiocaError::Config(format!(
+                        "Cannot read root PEM from {}: {}",
+                        path.display(),
+                        err
+                    ))
+                })?;
+                Some(pem)
+            }
+            Some(RootPemSource::Bytes(bytes)) => {
+                let pem = String::from_utf8(bytes).map_err(|err| {
+                    NiocaError::Config(format!("Root PEM is not valid UTF-8: {}", err))
+                })?;
+                Some(pem)
+            }
+        };
+        let root_cert = match &root_pem {
+            None => None,
+            Some(pem) => {
+                let cert = reqwest::tls::Certificate::from_pem(pem.as_bytes()).map_err(|err| {
+                    NiocaError::Config(format!("Cannot build Root TLS from given PEM: {}", err))
+                })?;
+                Some(cert)
+            }
+        };
+
+        let pin_root_fingerprint = self
+            .pin_root_fingerprint
+            .as_deref()
+            .map(normalize_fingerprint)
+            .transpose()?;
+        let pin_intermediate_fingerprint = self
+            .pin_intermediate_fingerprint
+            .as_deref()
+            .map(normalize_fingerprint)
+            .transpose()?;
+        if pin_root_fingerprint.is_some() || pin_intermediate_fingerprint.is_some() {
+            match &root_pem {
+                None => {
+                    return Err(NiocaError::Config(
+                        "Certificate pinning requires a root PEM".to_string(),
+                    ))
+                }
+                Some(pem) => {
+                    if let Some(pin) = &pin_root_fingerprint {
+                        verify_root_pin(pem, pin)?;
+                    }
+                }
+            }
+        }
+
+        if self.client_auth != ClientAuth::None && root_pem.is_none() {
+            return Err(NiocaError::Config(
+                "Client certificate authentication requires a root PEM".to_string(),
+            ));
+        }
+
+        let renewal = self.renewal.unwrap_or_default();
+        renewal.validate()?;
+
+        debug!("Nioca URL: {}", url);
+        Ok(NiocaConfig {
+            url,
+            url_ssh,
+            url_x509,
+            root_cert,
+            root_pem,
+            api_key_ssh: self.api_key_ssh,
+            api_key_x509: self.api_key_x509,
+            connect_timeout: self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: self.request_timeout,
+            pin_root_fingerprint,
+            pin_intermediate_fingerprint,
+            renewal,
+            clock_skew: self.clock_skew.unwrap_or(DEFAULT_CLOCK_SKEW),
+            client_auth: self.client_auth,
+            pkcs12_password: self.pkcs12_password,
+            #[cfg(feature = "ssh")]
+            ssh_ca_fingerprints: self.ssh_ca_fingerprints,
+            #[cfg(feature = "ssh")]
+            ssh_trust_response_ca: self.ssh_trust_response_ca,
+            #[cfg(feature = "ssh")]
+            ssh_cert_type: self.ssh_cert_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint;
+    use crate::test_util::ca;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn builder() -> NiocaConfigBuilder {
+        NiocaConfig::builder().url("https://ca.local.dev:8443/")
+    }
+
+    fn assert_config_err(res: Result<NiocaConfig, NiocaError>) {
+        assert!(matches!(res, Err(NiocaError::Config(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn test_build() {
+        let config = builder()
+            .client_id_x509("id")
+            .api_key_x509("key")
+            .build()
+            .unwrap();
+        assert_eq!(config.url, "https://ca.local.dev:8443");
+        assert_eq!(
+            config.url_x509.as_deref(),
+            Some("https://ca.local.dev:8443/api/clients/x509/id/cert")
+        );
+        assert_eq!(config.url_ssh, None);
+        assert_eq!(config.renewal, RenewalPolicy::default());
+        assert_eq!(config.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(config.clock_skew, DEFAULT_CLOCK_SKEW);
+    }
+
+    #[test]
+    fn test_build_invalid_url_and_keys() {
+        assert_config_err(NiocaConfig::builder().build());
+        assert_config_err(NiocaConfig::builder().url("/").build());
+        assert_config_err(NiocaConfig::builder().url("http://ca.local.dev").build());
+        assert_config_err(NiocaConfig::builder().url("ca.local.dev").build());
+
+        assert_config_err(builder().api_key_x509("").build());
+        assert_config_err(builder().api_key_ssh("  ").build());
+    }
+
+    #[test]
+    fn test_build_pinning() {
+        let root_pem = ca("Root").serialize_pem().unwrap();
+        let pin = fingerprint(root_pem.as_bytes());
+
+        let config = builder()
+            .root_pem(root_pem.clone())
+            .pin_root_fingerprint(pin.trim_start_matches("sha256:").to_uppercase())
+            .build()
+            .unwrap();
+        assert_eq!(config.pin_root_fingerprint, Some(pin.clone()));
+
+        // invalid format, no root PEM, and a pin which does not match the root
+        assert_config_err(
+            builder()
+                .root_pem(root_pem.clone())
+                .pin_root_fingerprint("abc")
+                .build(),
+        );
+        assert_config_err(builder().pin_root_fingerprint(pin.clone()).build());
+        assert_config_err(builder().pin_intermediate_fingerprint(pin).build());
+        let other = fingerprint(b"other");
+        assert_config_err(
+            builder()
+                .root_pem(root_pem)
+                .pin_root_fingerprint(other)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_build_invalid_policy_and_client_auth() {
+        let policy = RenewalPolicy {
+            renew_at: 0.0,
+            ..Default::default()
+        };
+        assert_config_err(builder().renewal_policy(policy).build());
+        assert_config_err(builder().client_auth(ClientAuth::Required).build());
+    }
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<NiocaConfig, NiocaError> {
+        let vars = vars.iter().copied().collect::<HashMap<_, _>>();
+        NiocaConfigBuilder::from_lookup(|name| vars.get(name).map(|value| value.to_string()))?
+            .build()
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        assert_config_err(from_vars(&[]));
+        assert_config_err(from_vars(&[
+            ("NIOCA_URL", "https://ca.local.dev"),
+            ("ERROR_TIMEOUT", "1m"),
+        ]));
+
+        let config = from_vars(&[
+            ("NIOCA_URL", "https://ca.local.dev"),
+            ("NIOCA_X509_CLIENT_ID", "id"),
+            ("NIOCA_X509_API_KEY", ""),
+            ("ERROR_TIMEOUT", " 5 "),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.url_x509.as_deref(),
+            Some("https://ca.local.dev/api/clients/x509/id/cert")
+        );
+        assert_eq!(config.renewal.backoff_initial, Duration::from_secs(5));
+        assert!(config.api_key_x509.is_none());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::ErrorResponseType;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The error type returned by all public functions of the Nioca client libraries.
+///
+/// The variants are split up in a way that makes it possible to decide between retrying,
+/// alerting and aborting without the need to match on error messages.
+#[derive(Debug, Clone, Error)]
+pub enum NiocaError {
+    /// The request could not be sent or the connection failed, for instance because of a
+    /// TLS error or a timeout.
+    #[error("Error connecting to Nioca: {0}")]
+    Transport(String),
+
+    /// Nioca answered with '405 Method Not Allowed', which happens if it is sealed.
+    #[error(
+        "'405 Method Not Allowed' from Nioca Server. This usually happens if Nioca is sealed. \
+        Check and unseal if necessary."
+    )]
+    Sealed,
+
+    /// Nioca rate-limits this client. `retry_after` contains the value of the `Retry-After`
+    /// header, if Nioca sent one.
+    #[error("429 Too Many Requests from Nioca Server - retry after: {retry_after:?}")]
+    TooManyRequests { retry_after: Option<Duration> },
+
+    /// Nioca returned a proper `ErrorResponse`.
+    #[error("{status} - {typ:?}: {message}")]
+    Server {
+        status: u16,
+        typ: ErrorResponseType,
+        message: String,
+    },
+
+    /// The response could not be decoded into the expected type.
+    #[error("Error decoding response: {0}")]
+    Decode(String),
+
+    /// The fetched certificate material cannot be used, for instance because it cannot be
+    /// parsed or the private key does not match.
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+
+    /// The fetched certificate material is consistent in itself, but must not be used.
+    #[error("Certificate validation failed: {0}")]
+    Validation(#[from] ValidationError),
+
+    /// The given configuration is invalid or incomplete.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// No certificate could be fetched in time.
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    /// A background renewal task panicked or has been aborted.
+    #[error("The renewal task died: {0}")]
+    TaskDied(String),
+}
+
+impl NiocaError {
+    /// Returns `true` if it makes sense to retry the same request later on.
+    ///
+    /// A bad configuration or invalid credentials will not fix themselves, while a sealed Nioca
+    /// or a broken connection usually will.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Transport(_)
+            | Self::Sealed
+            | Self::TooManyRequests { .. }
+            | Self::Decode(_)
+            | Self::InvalidCertificate(_)
+            | Self::Validation(_)
+            | Self::Timeout(_) => true,
+            Self::Server { typ, .. } => !matches!(
+                typ,
+                ErrorResponseType::BadRequest
+                    | ErrorResponseType::Forbidden
+                    | ErrorResponseType::InvalidToken
+                    | ErrorResponseType::NotFound
+                    | ErrorResponseType::Unauthorized
+            ),
+            Self::Config(_) | Self::TaskDied(_) => false,
+        }
+    }
+
+    /// Returns the duration Nioca asked us to wait before the next request, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::TooManyRequests { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// The reasons why fetched certificate material is rejected before it is published or
+/// written to disk.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationError {
+    /// The certificate does not chain up to the configured Nioca root.
+    #[error("The certificate does not chain up to the Nioca root: {0}")]
+    UntrustedChain(String),
+
+    /// The private key does not belong to the certificate.
+    #[error("The private key does not match the certificate: {0}")]
+    KeyMismatch(String),
+
+    /// The certificate is not valid yet. Contains its `not_before` as a unix timestamp.
+    #[error("The certificate is not valid before {0}")]
+    NotYetValid(i64),
+
+    /// The certificate has expired. Contains its `not_after` as a unix timestamp.
+    #[error("The certificate has expired at {0}")]
+    Expired(i64),
+
+    /// The SSH certificate has not been signed by one of the expected CA keys.
+    #[error("The SSH certificate is not signed by a trusted CA: {0}")]
+    UntrustedCa(String),
+
+    /// The SSH certificate does not contain any principals, which would make it valid for
+    /// every user or host.
+    #[error("The SSH certificate has no principals")]
+    NoPrincipals,
+
+    /// The SSH certificate type differs from the configured one.
+    #[error("Expected an SSH {expected} certificate but got a {actual} certificate")]
+    CertTypeMismatch { expected: String, actual: String },
+
+    /// The fingerprint sent by Nioca does not match the certificate.
+    #[error("Expected fingerprint {expected} but the certificate has {actual}")]
+    FingerprintMismatch { expected: String, actual: String },
+}
+
+impl From<reqwest::Error> for NiocaError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_decode() {
+            Self::Decode(value.to_string())
+        } else {
+            Self::Transport(value.to_string())
+        }
+    }
+}
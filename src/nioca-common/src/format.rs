@@ -0,0 +1,387 @@
+use crate::tls::{der_to_pem, pem_to_certs, pem_to_private_key};
+use crate::x509::{CertX509Response, X509CertFormat};
+use crate::{NiocaError, Secret};
+use base64::engine::general_purpose;
+use base64::Engine;
+use p12_keystore::{KeyStore, KeyStoreEntry, PrivateKeyChain};
+use rustls::{Certificate, PrivateKey};
+use std::fmt::{Debug, Formatter};
+use zeroize::{Zeroize, Zeroizing};
+
+const OID_RSA_ENCRYPTION: [u8; 11] = [
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+];
+const OID_EC_PUBLIC_KEY: [u8; 9] = [0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
+/// X509 material decoded from any of the formats Nioca can deliver.
+#[derive(Clone)]
+pub struct X509Bundle {
+    /// The leaf certificate followed by the rest of the chain in the delivered order
+    pub chain: Vec<Certificate>,
+    /// The private key in PKCS8 DER format, which is wiped from memory on drop
+    pub key: PrivateKey,
+}
+
+impl Debug for X509Bundle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("X509Bundle")
+            .field("chain", &self.chain)
+            .field("key", &"<REDACTED>")
+            .finish()
+    }
+}
+
+impl Drop for X509Bundle {
+    fn drop(&mut self) {
+        self.key.0.zeroize();
+    }
+}
+
+impl X509Bundle {
+    /// Decodes the certificate, chain and key from the given response depending on its
+    /// `cert_format`.
+    ///
+    /// - `PEM`: all fields contain PEM blocks
+    /// - `DER`: all fields contain base64 encoded DER, separated by whitespace if there is
+    ///   more than one certificate
+    /// - `PKCS12`: `cert` contains a base64 encoded PKCS12 archive with the key and the
+    ///   chain, which is decrypted with `pkcs12_password` (default: empty)
+    pub fn from_response(
+        certs: &CertX509Response,
+        pkcs12_password: Option<&str>,
+    ) -> Result<Self, NiocaError> {
+        let (chain, key) = match certs.cert_format {
+            X509CertFormat::Pem => {
+                let mut chain = pem_to_certs(&certs.cert)?;
+                chain.append(&mut pem_to_certs(&certs.cert_chain)?);
+                let mut key = pem_to_private_key(certs.key.expose_secret())?;
+                let pkcs8 = key_to_pkcs8(&key.0);
+                key.0.zeroize();
+                (chain, pkcs8?)
+            }
+            X509CertFormat::Der => {
+                let mut chain = b64_to_certs(&certs.cert)?;
+                chain.append(&mut b64_to_certs(&certs.cert_chain)?);
+                let key = Zeroizing::new(b64_decode(certs.key.expose_secret())?);
+                (chain, key_to_pkcs8(&key)?)
+            }
+            X509CertFormat::PKCS12 => {
+                let archive = b64_decode(&certs.cert)?;
+                let keystore = KeyStore::from_pkcs12(&archive, pkcs12_password.unwrap_or(""))
+                    .map_err(|err| {
+                        NiocaError::InvalidCertificate(format!(
+                            "Cannot decrypt PKCS12 archive - wrong password? {}",
+                            err
+                        ))
+                    })?;
+                let (_, key_chain) = keystore.private_key_chain().ok_or_else(|| {
+                    NiocaError::InvalidCertificate(
+                        "No private key found in the PKCS12 archive".to_string(),
+                    )
+                })?;
+
+                let mut chain = key_chain
+                    .chain()
+                    .iter()
+                    .map(|cert| Certificate(cert.as_der().to_vec()))
+                    .collect::<Vec<_>>();
+                chain.append(&mut b64_to_certs(&certs.cert_chain)?);
+                (chain, key_to_pkcs8(key_chain.key())?)
+            }
+        };
+
+        if chain.is_empty() {
+            return Err(NiocaError::InvalidCertificate(
+                "No certificate found".to_string(),
+            ));
+        }
+        Ok(Self {
+            chain,
+            key: PrivateKey(key),
+        })
+    }
+
+    /// Returns the leaf certificate.
+    pub fn leaf(&self) -> &Certificate {
+        &self.chain[0]
+    }
+
+    /// Encodes the whole bundle as a PKCS12 archive protected with the given password.
+    pub fn to_pkcs12(&self, password: &str) -> Result<Vec<u8>, NiocaError> {
+        let chain = self
+            .chain
+            .iter()
+            .map(|cert| p12_keystore::Certificate::from_der(&cert.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| {
+                NiocaError::InvalidCertificate(format!("Cannot parse certificate: {}", err))
+            })?;
+        let local_key_id = ring::digest::digest(&ring::digest::SHA256, &self.leaf().0);
+
+        let mut keystore = KeyStore::new();
+        keystore.add_entry(
+            "nioca",
+            KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(
+                &self.key.0,
+                &local_key_id.as_ref()[..20],
+                chain,
+            )),
+        );
+        keystore.writer(password).write().map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot build PKCS12 archive: {}", err))
+        })
+    }
+
+    /// Converts the bundle into a `CertX509Response` with the given format. Everything apart
+    /// from the certificate material is taken from `template`.
+    pub fn to_response(
+        &self,
+        format: X509CertFormat,
+        pkcs12_password: Option<&str>,
+        template: &CertX509Response,
+    ) -> Result<CertX509Response, NiocaError> {
+        let (cert, cert_chain, key) = match format {
+            X509CertFormat::Pem => (
+                der_to_pem("CERTIFICATE", &self.leaf().0),
+                self.chain[1..]
+                    .iter()
+                    .map(|cert| der_to_pem("CERTIFICATE", &cert.0))
+                    .collect(),
+                der_to_pem("PRIVATE KEY", &self.key.0).into(),
+            ),
+            X509CertFormat::Der => (
+                general_purpose::STANDARD.encode(&self.leaf().0),
+                self.chain[1..]
+                    .iter()
+                    .map(|cert| general_purpose::STANDARD.encode(&cert.0))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                general_purpose::STANDARD.encode(&self.key.0).into(),
+            ),
+            X509CertFormat::PKCS12 => (
+                general_purpose::STANDARD.encode(self.to_pkcs12(pkcs12_password.unwrap_or(""))?),
+                String::default(),
+                Secret::default(),
+            ),
+        };
+
+        Ok(CertX509Response {
+            cert,
+            cert_fingerprint: template.cert_fingerprint.clone(),
+            cert_chain,
+            key,
+            cert_format: format,
+            not_after: template.not_after,
+        })
+    }
+}
+
+fn b64_decode(value: &str) -> Result<Vec<u8>, NiocaError> {
+    let value = value
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    general_purpose::STANDARD
+        .decode(value)
+        .map_err(|err| NiocaError::InvalidCertificate(format!("Invalid base64: {}", err)))
+}
+
+/// Decodes whitespace separated base64 values, each of which may contain one or more
+/// concatenated DER certificates.
+fn b64_to_certs(value: &str) -> Result<Vec<Certificate>, NiocaError> {
+    let mut certs = Vec::new();
+    for part in value.split_whitespace() {
+        let der = b64_decode(part)?;
+        for cert in der_split(&der)? {
+            certs.push(Certificate(cert.to_vec()));
+        }
+    }
+    Ok(certs)
+}
+
+/// Reads the tag, the header length and the content length of the DER element at the
+/// beginning of `data`.
+fn der_header(data: &[u8]) -> Result<(u8, usize, usize), NiocaError> {
+    let err = || NiocaError::InvalidCertificate("Invalid DER encoding".to_string());
+
+    let tag = *data.first().ok_or_else(err)?;
+    let first = *data.get(1).ok_or_else(err)?;
+    if first < 0x80 {
+        return Ok((tag, 2, first as usize));
+    }
+
+    let len_bytes = (first & 0x7f) as usize;
+    if len_bytes == 0 || len_bytes > 4 || data.len() < 2 + len_bytes {
+        return Err(err());
+    }
+    let len = data[2..2 + len_bytes]
+        .iter()
+        .fold(0usize, |len, b| (len << 8) | *b as usize);
+    Ok((tag, 2 + len_bytes, len))
+}
+
+/// Splits concatenated DER elements.
+fn der_split(mut data: &[u8]) -> Result<Vec<&[u8]>, NiocaError> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (_, header, len) = der_header(data)?;
+        let end = header + len;
+        if data.len() < end {
+            return Err(NiocaError::InvalidCertificate(
+                "Truncated DER element".to_string(),
+            ));
+        }
+        elements.push(&data[..end]);
+        data = &data[end..];
+    }
+    Ok(elements)
+}
+
+/// Returns the children of a DER SEQUENCE.
+fn der_sequence(data: &[u8]) -> Result<Vec<&[u8]>, NiocaError> {
+    let (tag, header, len) = der_header(data)?;
+    if tag != 0x30 || data.len() < header + len {
+        return Err(NiocaError::InvalidCertificate(
+            "Expected a DER SEQUENCE".to_string(),
+        ));
+    }
+    der_split(&data[header..header + len])
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Converts a PKCS1 (RSA) or SEC1 (EC) private key into PKCS8. PKCS8 keys are returned
+/// unchanged.
+pub(crate) fn key_to_pkcs8(der: &[u8]) -> Result<Vec<u8>, NiocaError> {
+    let children = der_sequence(der)?;
+    let second_tag = children.get(1).and_then(|c| c.first()).copied();
+
+    let algorithm = match second_tag {
+        // PKCS8: version, AlgorithmIdentifier, key
+        Some(0x30) => return Ok(der.to_vec()),
+        // PKCS1: version, modulus, ...
+        Some(0x02) => {
+            let mut alg = OID_RSA_ENCRYPTION.to_vec();
+            alg.extend_from_slice(&[0x05, 0x00]);
+            alg
+        }
+        // SEC1: version, key, [0] curve, [1] public key
+        Some(0x04) => {
+            let curve = children
+                .iter()
+                .find(|c| c.first() == Some(&0xa0))
+                .ok_or_else(|| {
+                    NiocaError::InvalidCertificate(
+                        "The EC private key does not contain its curve".to_string(),
+                    )
+                })?;
+            let (_, header, _) = der_header(curve)?;
+            let mut alg = OID_EC_PUBLIC_KEY.to_vec();
+            alg.extend_from_slice(&curve[header..]);
+            alg
+        }
+        _ => {
+            return Err(NiocaError::InvalidCertificate(
+                "Unknown private key format".to_string(),
+            ))
+        }
+    };
+
+    let mut content = vec![0x02, 0x01, 0x00];
+    content.append(&mut der_tlv(0x30, &algorithm));
+    content.append(&mut der_tlv(0x04, der));
+    Ok(der_tlv(0x30, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ca, leaf};
+    use crate::tls::verify_key_matches;
+    use pretty_assertions::assert_eq;
+
+    fn pem_response() -> CertX509Response {
+        let root = ca("Root");
+        let mut certs = leaf(&root, "Leaf").response(root.serialize_pem().unwrap());
+        certs.not_after = 1337;
+        certs
+    }
+
+    #[test]
+    fn test_der_roundtrip() {
+        let pem = pem_response();
+        let bundle = X509Bundle::from_response(&pem, None).unwrap();
+        assert_eq!(bundle.chain.len(), 2);
+
+        let der = bundle.to_response(X509CertFormat::Der, None, &pem).unwrap();
+        assert_eq!(der.cert_format, X509CertFormat::Der);
+        assert_eq!(der.not_after, 1337);
+
+        let decoded = X509Bundle::from_response(&der, None).unwrap();
+        assert_eq!(decoded.chain, bundle.chain);
+        assert_eq!(decoded.key, bundle.key);
+
+        let back = decoded
+            .to_response(X509CertFormat::Pem, None, &der)
+            .unwrap();
+        assert_eq!(back.cert, pem.cert);
+        assert_eq!(back.cert_chain, pem.cert_chain);
+    }
+
+    #[test]
+    fn test_pkcs12_roundtrip_with_password() {
+        let pem = pem_response();
+        let bundle = X509Bundle::from_response(&pem, None).unwrap();
+
+        let p12 = bundle
+            .to_response(X509CertFormat::PKCS12, Some("secret"), &pem)
+            .unwrap();
+        assert!(p12.key.is_empty());
+
+        let decoded = X509Bundle::from_response(&p12, Some("secret")).unwrap();
+        assert_eq!(decoded.chain, bundle.chain);
+        verify_key_matches(decoded.leaf(), &decoded.key).unwrap();
+
+        assert!(matches!(
+            X509Bundle::from_response(&p12, Some("wrong")),
+            Err(NiocaError::InvalidCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn test_sec1_key_is_converted() {
+        // rcgen only creates PKCS8, so we unwrap the SEC1 key and add the curve back in
+        let pem = pem_response();
+        let pkcs8 = pem_to_private_key(pem.key.expose_secret()).unwrap().0;
+        let children = der_sequence(&pkcs8).unwrap();
+        let curve = der_sequence(children[1]).unwrap()[1];
+        let (_, header, _) = der_header(children[2]).unwrap();
+        let sec1 = der_sequence(&children[2][header..]).unwrap();
+
+        let mut content = sec1[0].to_vec();
+        content.extend_from_slice(sec1[1]);
+        content.append(&mut der_tlv(0xa0, curve));
+        for child in &sec1[2..] {
+            content.extend_from_slice(child);
+        }
+        let sec1 = der_tlv(0x30, &content);
+
+        let converted = PrivateKey(key_to_pkcs8(&sec1).unwrap());
+        let leaf = pem_to_certs(&pem.cert).unwrap().remove(0);
+        verify_key_matches(&leaf, &converted).unwrap();
+        assert_eq!(key_to_pkcs8(&pkcs8).unwrap(), pkcs8);
+    }
+}
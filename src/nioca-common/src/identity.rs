@@ -0,0 +1,127 @@
+use crate::renewal::{spawn_renewal, CertSink, RenewalHandle};
+use crate::tls::{certs_to_pem, der_to_pem, server_cert_and_key};
+use crate::x509::CertX509Response;
+use crate::{NiocaConfig, NiocaError, Secret, VERSION};
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// A cheap to clone handle to a `reqwest::Client`, which presents the latest Nioca client
+/// certificate on every outgoing connection.
+///
+/// The client is rebuilt after each renewal. It only trusts the Nioca root, because it is
+/// meant for calls between services which all hold Nioca certificates.
+#[derive(Debug, Clone)]
+pub struct NiocaClientIdentity {
+    rx: watch::Receiver<Option<reqwest::Client>>,
+}
+
+/// Rebuilds the client with each renewed certificate.
+struct Sink {
+    config: NiocaConfig,
+    tx: watch::Sender<Option<reqwest::Client>>,
+}
+
+#[async_trait]
+impl CertSink for Sink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        let client = NiocaClientIdentity::build_client(&self.config, certs)?;
+        self.tx.send_replace(Some(client));
+        Ok(())
+    }
+}
+
+impl From<&RenewalHandle<reqwest::Client>> for NiocaClientIdentity {
+    fn from(handle: &RenewalHandle<reqwest::Client>) -> Self {
+        Self {
+            rx: handle.receiver(),
+        }
+    }
+}
+
+impl NiocaClientIdentity {
+    /// Spawns the certificate renewal, which rebuilds the client with each certificate.
+    ///
+    /// The returned handle controls the renewal task like the ones of the framework
+    /// integrations. Create the client handle from it with `NiocaClientIdentity::from`.
+    ///
+    /// Requires a root PEM in the given config.
+    pub fn spawn(config: NiocaConfig) -> Result<RenewalHandle<reqwest::Client>, NiocaError> {
+        if config.root_pem.is_none() {
+            return Err(NiocaError::Config(
+                "NiocaClientIdentity requires a root PEM".to_string(),
+            ));
+        }
+
+        let (tx, rx) = watch::channel(None);
+        let sink = Sink {
+            config: config.clone(),
+            tx,
+        };
+        let task = spawn_renewal(config, sink)?;
+        Ok(RenewalHandle::new(rx, task))
+    }
+
+    /// Spawns the renewal and waits for the first client, see [RenewalHandle::wait_ready].
+    pub async fn spawn_and_wait(
+        config: NiocaConfig,
+        timeout: Duration,
+    ) -> Result<RenewalHandle<reqwest::Client>, NiocaError> {
+        Self::spawn(config)?.into_ready(timeout).await
+    }
+
+    /// Returns the client with the latest certificate, or `None` if the first certificate
+    /// has not been fetched yet.
+    ///
+    /// Fetch the client for each call or batch of calls instead of storing it, to always
+    /// present a valid certificate.
+    pub fn client(&self) -> Option<reqwest::Client> {
+        self.rx.borrow().clone()
+    }
+
+    /// Returns a receiver, which is notified about each rebuilt client.
+    pub fn receiver(&self) -> watch::Receiver<Option<reqwest::Client>> {
+        self.rx.clone()
+    }
+
+    /// Converts the given certificate into a `reqwest::Identity`.
+    ///
+    /// The chain is ordered leaf first and the private key is checked to belong to the leaf.
+    pub fn identity(certs: &CertX509Response) -> Result<reqwest::Identity, NiocaError> {
+        let (chain, key) = server_cert_and_key(certs)?;
+        let pem = Secret::new(format!(
+            "{}{}",
+            certs_to_pem(&chain),
+            der_to_pem("PRIVATE KEY", &key.0)
+        ));
+        reqwest::Identity::from_pem(pem.expose_secret().as_bytes()).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot build reqwest Identity: {}", err))
+        })
+    }
+
+    /// Builds a `reqwest::Client`, which presents the given certificate and only trusts the
+    /// Nioca root from the given config. The timeouts are taken from the config as well.
+    pub fn build_client(
+        config: &NiocaConfig,
+        certs: &CertX509Response,
+    ) -> Result<reqwest::Client, NiocaError> {
+        let root_cert = config.root_cert.clone().ok_or_else(|| {
+            NiocaError::Config("NiocaClientIdentity requires a root PEM".to_string())
+        })?;
+
+        let mut client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .https_only(true)
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(root_cert)
+            .identity(Self::identity(certs)?)
+            .user_agent(format!("Nioca Client {}", VERSION));
+        if let Some(timeout) = config.request_timeout {
+            client = client.timeout(timeout);
+        }
+
+        client.build().map_err(|err| {
+            NiocaError::Config(format!("Building reqwest client with identity: {}", err))
+        })
+    }
+}
@@ -1,15 +1,36 @@
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use std::env;
 use std::time::Duration;
-use tracing::debug;
 
+pub use config::{NiocaConfig, NiocaConfigBuilder};
+pub use error::{NiocaError, ValidationError};
+pub use identity::NiocaClientIdentity;
+pub use pinning::fingerprint;
+pub use renewal::{
+    spawn_renewal, CertSink, RenewalHandle, RenewalPolicy, RenewalStatus, RenewalTask,
+};
+pub use secret::Secret;
+pub use tls::{ClientAuth, PeerIdentity};
+
+mod config;
+mod error;
+pub mod format;
+mod identity;
+mod pinning;
+mod renewal;
+mod secret;
 #[cfg(feature = "ssh")]
 pub mod ssh;
 
+#[cfg(test)]
+mod test_util;
+pub mod tls;
 pub mod x509;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+#[deprecated(note = "panics on invalid input - use `RenewalPolicy::backoff_initial` instead")]
 pub static ERR_TIMEOUT: once_cell::sync::Lazy<u64> = once_cell::sync::Lazy::new(|| {
     env::var("ERROR_TIMEOUT")
         .unwrap_or_else(|_| "60".to_string())
@@ -44,93 +65,110 @@ pub enum ErrorResponseType {
     TooManyRequests,
 }
 
-#[derive(Debug, Clone)]
-pub struct NiocaConfig {
-    pub url: String,
-    pub url_ssh: Option<String>,
-    pub url_x509: Option<String>,
-    pub root_cert: Option<reqwest::Certificate>,
-    pub root_pem: Option<String>,
-    pub api_key_ssh: Option<String>,
-    pub api_key_x509: Option<String>,
+#[derive(Debug, Clone, Deserialize)]
+pub struct NiocaErrorResponse {
+    pub typ: String,
+    pub message: String,
 }
 
-impl NiocaConfig {
-    pub async fn from_env() -> Self {
-        dotenvy::dotenv().ok();
-        let mut url = env::var("NIOCA_URL").expect("NIOCA_URL is not set");
-        if url.ends_with('/') {
-            let _ = url.split_off(url.len() - 1);
-        }
-
-        let api_key_x509 = env::var("NIOCA_X509_API_KEY").map(Some).unwrap_or(None);
-        let client_id_x509 = env::var("NIOCA_X509_CLIENT_ID").map(Some).unwrap_or(None);
-        let url_x509 = client_id_x509.map(|id| format!("{}/api/clients/x509/{}/cert", url, id));
-
-        let api_key_ssh = env::var("NIOCA_SSH_API_KEY").map(Some).unwrap_or(None);
-        let client_id_ssh = env::var("NIOCA_SSH_CLIENT_ID").map(Some).unwrap_or(None);
-        let url_ssh = client_id_ssh.map(|id| format!("{}/api/clients/ssh/{}/cert", url, id));
-
-        let (root_pem, root_cert) = match env::var("NIOCA_ROOT_PEM") {
-            Ok(root_pem) => {
-                let root_cert = reqwest::tls::Certificate::from_pem(root_pem.as_bytes())
-                    .expect("Cannot build Root TLS from given NIOCA_ROOT_PEM");
-                (Some(root_pem), Some(root_cert))
-            }
-            #[cfg(not(feature = "cli"))]
-            Err(_) => (None, None),
-            #[cfg(feature = "cli")]
-            Err(_) => {
-                // if we do not have a configured env var, try to find an existing root PEM in
-                // the nioca config dir
-                match home::home_dir() {
-                    Some(path) => {
-                        let try_root_pem_path = format!("{}/.nioca/root.pem", path.display());
-                        match tokio::fs::read_to_string(&try_root_pem_path).await {
-                            Ok(root_pem) => {
-                                let root_cert =
-                                    reqwest::tls::Certificate::from_pem(root_pem.as_bytes())
-                                        .expect("Cannot build Root TLS from given NIOCA_ROOT_PEM");
-                                (Some(root_pem), Some(root_cert))
-                            }
-                            Err(_) => (None, None),
-                        }
-                    }
-                    None => (None, None),
-                }
-            }
-        };
-
-        debug!("Nioca URL: {}", url);
-        Self {
-            url,
-            url_ssh,
-            url_x509,
-            root_cert,
-            root_pem,
-            api_key_ssh,
-            api_key_x509,
-        }
+/// Converts a non-successful response from Nioca into the matching `NiocaError`.
+pub(crate) async fn error_from_response(resp: reqwest::Response) -> NiocaError {
+    let status = resp.status();
+
+    if status == reqwest::StatusCode::METHOD_NOT_ALLOWED {
+        return NiocaError::Sealed;
+    }
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        return NiocaError::TooManyRequests { retry_after };
+    }
+
+    match resp.json::<ErrorResponse>().await {
+        Ok(err) => NiocaError::Server {
+            status: status.as_u16(),
+            typ: err.typ,
+            message: err.message,
+        },
+        Err(err) => NiocaError::Decode(format!(
+            "{} - Error deserializing response into ErrorResponse: {}",
+            status, err
+        )),
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct NiocaErrorResponse {
-    pub typ: String,
-    pub message: String,
+/// Parses a `Retry-After` header, which is either given in seconds or as an HTTP date.
+/// A date in the past means that the request may be retried right away.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        date.signed_duration_since(now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
 }
 
-pub fn req_client(root_cert: Option<reqwest::Certificate>) -> reqwest::Client {
+pub fn req_client(config: &NiocaConfig) -> Result<reqwest::Client, NiocaError> {
     let mut client = reqwest::ClientBuilder::new()
-        .connect_timeout(Duration::from_secs(10))
+        .connect_timeout(config.connect_timeout)
         .https_only(true)
         .user_agent(format!("Nioca Client {}", VERSION));
 
-    if let Some(root_cert) = root_cert {
-        client = client.add_root_certificate(root_cert);
+    if let Some(timeout) = config.request_timeout {
+        client = client.timeout(timeout);
     }
+    if config.pin_root_fingerprint.is_some() || config.pin_intermediate_fingerprint.is_some() {
+        let root_pem = config.root_pem.as_deref().ok_or_else(|| {
+            NiocaError::Config("Certificate pinning requires a root PEM".to_string())
+        })?;
+        let tls = pinning::pinned_client_config(
+            root_pem,
+            config.pin_root_fingerprint.as_deref(),
+            config.pin_intermediate_fingerprint.clone(),
+        )?;
+        client = client.use_preconfigured_tls(tls);
+    } else if let Some(root_cert) = &config.root_cert {
+        client = client.add_root_certificate(root_cert.clone());
+    }
+
+    client.build().map_err(|err| {
+        NiocaError::Config(format!("Building reqwest client for fetch_cert: {}", err))
+    })
+}
 
-    client
-        .build()
-        .expect("Building reqwest client for fetch_cert")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+    }
 }
@@ -0,0 +1,284 @@
+use crate::NiocaError;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, Error, RootCertStore, ServerName};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::error;
+
+/// Calculates the SHA256 fingerprint of the given value in the same `sha256:<hex>` format
+/// Nioca uses.
+pub fn fingerprint(value: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, value);
+    format!("sha256:{}", hex::encode(digest.as_ref()))
+}
+
+/// Normalizes a given fingerprint into the `sha256:<hex>` format.
+///
+/// Accepts values with or without the `sha256:` prefix, upper case hex and `:` separators
+/// like they are shown by `openssl x509 -fingerprint`.
+pub(crate) fn normalize_fingerprint(value: &str) -> Result<String, NiocaError> {
+    let value = value.trim();
+    let hex = value
+        .strip_prefix("sha256:")
+        .or_else(|| value.strip_prefix("SHA256:"))
+        .unwrap_or(value)
+        .replace(':', "")
+        .to_lowercase();
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(NiocaError::Config(format!(
+            "'{}' is not a valid SHA256 fingerprint",
+            value
+        )));
+    }
+    Ok(format!("sha256:{}", hex))
+}
+
+/// Parses all certificates from the given PEM into DER.
+pub(crate) fn pem_certs_to_der(pem: &str) -> Result<Vec<Vec<u8>>, NiocaError> {
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes())
+        .map_err(|err| NiocaError::Decode(format!("Cannot parse PEM certificates: {}", err)))?;
+    if certs.is_empty() {
+        return Err(NiocaError::Decode(
+            "No certificate found in the given PEM".to_string(),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Makes sure that the configured root PEM matches the pinned root fingerprint and returns the
+/// DER of the pinned root.
+///
+/// Both the fingerprint of the PEM file itself, which Nioca shows during `fetch-root`, and the
+/// fingerprint of the DER encoded certificate are accepted. A PEM file pinned by its own
+/// fingerprint must contain exactly one certificate.
+pub(crate) fn verify_root_pin(root_pem: &str, pin: &str) -> Result<Vec<u8>, NiocaError> {
+    let mut ders = pem_certs_to_der(root_pem)?;
+    if fingerprint(root_pem.as_bytes()) == pin {
+        if ders.len() != 1 {
+            return Err(NiocaError::Config(
+                "A root PEM pinned by its file fingerprint must contain exactly one certificate"
+                    .to_string(),
+            ));
+        }
+        return Ok(ders.remove(0));
+    }
+    ders.into_iter()
+        .find(|der| fingerprint(der) == pin)
+        .ok_or_else(|| {
+            NiocaError::Config(
+                "The root PEM does not match the pinned root fingerprint".to_string(),
+            )
+        })
+}
+
+/// Verifies the server chain against the pinned Nioca root only and additionally checks that
+/// the presented chain contains the pinned intermediate, if any.
+struct PinningVerifier {
+    inner: WebPkiVerifier,
+    pin_intermediate: Option<String>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        if let Some(pin) = &self.pin_intermediate {
+            let found = intermediates
+                .iter()
+                .any(|cert| &fingerprint(&cert.0) == pin);
+            if !found {
+                error!("The presented certificate chain does not contain the pinned intermediate");
+                return Err(Error::General(
+                    "Pinned intermediate fingerprint not found in the certificate chain"
+                        .to_string(),
+                ));
+            }
+        }
+
+        Ok(verified)
+    }
+}
+
+/// Builds the verifier for connections to Nioca.
+///
+/// With a pinned root, only the certificate which matched the pin is trusted, even if the root
+/// PEM contains others. Otherwise all certificates from the root PEM are trusted.
+fn pinned_verifier(
+    root_pem: &str,
+    pin_root: Option<&str>,
+    pin_intermediate: Option<String>,
+) -> Result<PinningVerifier, NiocaError> {
+    let ders = match pin_root {
+        Some(pin) => vec![verify_root_pin(root_pem, pin)?],
+        None => pem_certs_to_der(root_pem)?,
+    };
+
+    let mut roots = RootCertStore::empty();
+    for der in ders {
+        roots.add(&Certificate(der)).map_err(|err| {
+            NiocaError::Config(format!("Invalid root certificate for pinning: {}", err))
+        })?;
+    }
+
+    Ok(PinningVerifier {
+        inner: WebPkiVerifier::new(roots, None),
+        pin_intermediate,
+    })
+}
+
+/// Builds a rustls `ClientConfig` which only trusts the pinned Nioca root.
+pub(crate) fn pinned_client_config(
+    root_pem: &str,
+    pin_root: Option<&str>,
+    pin_intermediate: Option<String>,
+) -> Result<ClientConfig, NiocaError> {
+    let verifier = pinned_verifier(root_pem, pin_root, pin_intermediate)?;
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ca, leaf, Leaf};
+    use crate::tls::der_to_pem;
+    use pretty_assertions::assert_eq;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    /// Returns the DER of (root, intermediate, leaf for localhost)
+    fn chain() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let root = ca("Root");
+        let int = ca("Intermediate");
+        (
+            Leaf::new(&root, None).der,
+            Leaf::new(&int, Some(&root)).der,
+            leaf(&int, "Leaf").der,
+        )
+    }
+
+    fn verify_chain(verifier: &PinningVerifier, int: &[u8], leaf: &[u8]) -> Result<(), Error> {
+        verifier
+            .verify_server_cert(
+                &Certificate(leaf.to_vec()),
+                &[Certificate(int.to_vec())],
+                &ServerName::try_from("localhost").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    fn verify(root: &[u8], int: &[u8], leaf: &[u8], pin: &str) -> Result<(), Error> {
+        let verifier = pinned_verifier(
+            &der_to_pem("CERTIFICATE", root),
+            None,
+            Some(pin.to_string()),
+        )
+        .unwrap();
+        verify_chain(&verifier, int, leaf)
+    }
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        let expected = format!("sha256:{}", HEX);
+        assert_eq!(normalize_fingerprint(HEX).unwrap(), expected);
+        assert_eq!(normalize_fingerprint(&expected).unwrap(), expected);
+        assert_eq!(
+            normalize_fingerprint(&format!(" SHA256:{} ", HEX.to_uppercase())).unwrap(),
+            expected
+        );
+
+        let colons = HEX
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap().to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(normalize_fingerprint(&colons).unwrap(), expected);
+
+        for invalid in ["", "sha256:", &HEX[..62], &format!("{}00", HEX)] {
+            assert!(normalize_fingerprint(invalid).is_err(), "{}", invalid);
+        }
+        let not_hex = format!("{}zz", &HEX[..62]);
+        assert!(matches!(
+            normalize_fingerprint(&not_hex),
+            Err(NiocaError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_root_pin() {
+        let (root, _, _) = chain();
+        let pem = der_to_pem("CERTIFICATE", &root);
+
+        assert_eq!(
+            verify_root_pin(&pem, &fingerprint(pem.as_bytes())).unwrap(),
+            root
+        );
+        assert_eq!(verify_root_pin(&pem, &fingerprint(&root)).unwrap(), root);
+        assert!(matches!(
+            verify_root_pin(&pem, &format!("sha256:{}", HEX)),
+            Err(NiocaError::Config(_))
+        ));
+
+        // the file fingerprint only pins a PEM with a single certificate
+        let (other_root, _, _) = chain();
+        let both = format!("{}{}", pem, der_to_pem("CERTIFICATE", &other_root));
+        assert!(verify_root_pin(&both, &fingerprint(both.as_bytes())).is_err());
+        assert_eq!(verify_root_pin(&both, &fingerprint(&root)).unwrap(), root);
+    }
+
+    #[test]
+    fn test_pinned_root_ignores_appended_roots() {
+        let (root, int, genuine_leaf) = chain();
+        let rogue_root = ca("Rogue Root");
+        let rogue_int = ca("Rogue Intermediate");
+        let rogue_int_der = Leaf::new(&rogue_int, Some(&rogue_root)).der;
+        let rogue_leaf = leaf(&rogue_int, "Rogue Leaf").der;
+
+        let root_pem = format!(
+            "{}{}",
+            der_to_pem("CERTIFICATE", &root),
+            der_to_pem("CERTIFICATE", &Leaf::new(&rogue_root, None).der)
+        );
+        let verifier = pinned_verifier(&root_pem, Some(&fingerprint(&root)), None).unwrap();
+
+        assert!(verify_chain(&verifier, &int, &genuine_leaf).is_ok());
+        assert!(verify_chain(&verifier, &rogue_int_der, &rogue_leaf).is_err());
+    }
+
+    #[test]
+    fn test_pinning_verifier() {
+        let (root, int, leaf) = chain();
+
+        assert!(verify(&root, &int, &leaf, &fingerprint(&int)).is_ok());
+        assert!(verify(&root, &int, &leaf, &format!("sha256:{}", HEX)).is_err());
+        // the pin must match an intermediate and never the end-entity certificate
+        assert!(verify(&root, &int, &leaf, &fingerprint(&leaf)).is_err());
+
+        // a chain up to another root is rejected, even if the intermediate pin matches
+        let (other_root, _, _) = chain();
+        assert!(verify(&other_root, &int, &leaf, &fingerprint(&int)).is_err());
+    }
+}
@@ -0,0 +1,590 @@
+use crate::x509::{fetch_cert_x509, CertX509Response, X509CertFormat};
+use crate::{auth_token, req_client, NiocaConfig, NiocaError, Secret};
+use async_trait::async_trait;
+use chrono::Utc;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info};
+
+/// Controls when certificates are renewed and how fast failed fetches are retried.
+///
+/// All renewal loops use the same policy to make sure that a lot of clients, which have been
+/// started at the same time, do not hit Nioca all at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenewalPolicy {
+    /// The fraction of the remaining lifetime after which a certificate should be renewed.
+    /// `0.9` means that a certificate which is valid for another 10 hours will be renewed
+    /// in 9 hours. (default: 0.9)
+    pub renew_at: f64,
+    /// The maximum random jitter as a fraction of the calculated interval.
+    /// `0.05` will randomly shift each interval by up to +-5%. (default: 0.05)
+    pub jitter: f64,
+    /// No interval will ever be shorter than this, which prevents busy loops for already
+    /// expired certificates. (default: 10 seconds)
+    pub min_interval: Duration,
+    /// No interval will ever be longer than this. (default: 7 days)
+    pub max_interval: Duration,
+    /// The retry interval after the first failed fetch, which will be doubled with each
+    /// further failure. (default: 60 seconds)
+    pub backoff_initial: Duration,
+    /// The maximum retry interval for failed fetches. (default: 1 hour)
+    pub backoff_max: Duration,
+}
+
+impl Default for RenewalPolicy {
+    fn default() -> Self {
+        Self {
+            renew_at: 0.9,
+            jitter: 0.05,
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(7 * 24 * 3600),
+            backoff_initial: Duration::from_secs(60),
+            backoff_max: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl RenewalPolicy {
+    pub(crate) fn validate(&self) -> Result<(), NiocaError> {
+        if !(self.renew_at > 0.0 && self.renew_at <= 1.0) {
+            return Err(NiocaError::Config(
+                "RenewalPolicy.renew_at must be in (0, 1]".to_string(),
+            ));
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            return Err(NiocaError::Config(
+                "RenewalPolicy.jitter must be in [0, 1)".to_string(),
+            ));
+        }
+        if self.min_interval > self.max_interval {
+            return Err(NiocaError::Config(
+                "RenewalPolicy.min_interval must not be greater than max_interval".to_string(),
+            ));
+        }
+        if self.backoff_initial > self.backoff_max {
+            return Err(NiocaError::Config(
+                "RenewalPolicy.backoff_initial must not be greater than backoff_max".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the time to wait until a certificate expiring at `not_after` (unix timestamp
+    /// in seconds) should be renewed.
+    pub fn next_renewal(&self, not_after: i64) -> Duration {
+        let remaining = not_after.saturating_sub(Utc::now().timestamp()).max(0) as f64;
+        self.finalize(remaining * self.renew_at)
+    }
+
+    /// Returns the time to wait before the next try after `failures` failed fetches in a row.
+    ///
+    /// A `retry_after` sent by Nioca is always respected, even if it exceeds `backoff_max`.
+    pub fn next_retry(&self, failures: u32, retry_after: Option<Duration>) -> Duration {
+        let exp = failures.saturating_sub(1).min(31);
+        let backoff = self
+            .backoff_initial
+            .saturating_mul(2u32.pow(exp))
+            .min(self.backoff_max);
+        let wait = self.finalize(backoff.as_secs_f64());
+
+        match retry_after {
+            Some(retry_after) if retry_after > wait => retry_after,
+            _ => wait,
+        }
+    }
+
+    fn finalize(&self, secs: f64) -> Duration {
+        let secs = if self.jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+            secs * factor
+        } else {
+            secs
+        };
+
+        Duration::from_secs_f64(secs.max(0.0)).clamp(self.min_interval, self.max_interval)
+    }
+}
+
+/// Receives every freshly fetched X509 certificate from the renewal engine.
+///
+/// This is the only thing a framework integration needs to implement. Fetching, scheduling
+/// and retries are all handled by [spawn_renewal]. Certificates are always converted to PEM
+/// before they are handed over, no matter which format Nioca delivers.
+///
+/// Each certificate is checked with [CertX509Response::validate] against the root of the
+/// `NiocaConfig` first. Rejected certificates never reach the sink, which means it simply
+/// keeps serving the previous one.
+#[async_trait]
+pub trait CertSink: Send + 'static {
+    /// Converts and publishes the given certificate.
+    ///
+    /// If this returns an error, the certificate is dropped and the fetch will be retried
+    /// on the error schedule of the [RenewalPolicy].
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError>;
+}
+
+#[async_trait]
+impl CertSink for watch::Sender<Option<CertX509Response>> {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        self.send_replace(Some(certs.clone()));
+        Ok(())
+    }
+}
+
+/// The current state of a renewal task.
+#[derive(Debug, Clone)]
+pub enum RenewalStatus {
+    /// The first certificate has not been fetched yet.
+    Starting,
+    /// The last fetch succeeded and the certificate has been accepted.
+    Renewed {
+        not_after: i64,
+        next_renewal: Duration,
+    },
+    /// The last fetch failed or the certificate has been rejected.
+    Failed {
+        error: NiocaError,
+        failures: u32,
+        retry_in: Duration,
+    },
+    /// The renewal task has been shut down.
+    Stopped,
+}
+
+/// A running renewal task spawned by [spawn_renewal].
+///
+/// Dropping it does *not* stop the task. Use [RenewalTask::shutdown] for that.
+#[derive(Debug)]
+pub struct RenewalTask {
+    status: watch::Receiver<RenewalStatus>,
+    shutdown: Arc<Notify>,
+    join: JoinHandle<()>,
+}
+
+impl RenewalTask {
+    /// Returns a receiver for status updates of this task.
+    pub fn status(&self) -> watch::Receiver<RenewalStatus> {
+        self.status.clone()
+    }
+
+    /// Returns `false` if the task has stopped, either after a shutdown or because it panicked.
+    pub fn is_running(&self) -> bool {
+        !self.join.is_finished()
+    }
+
+    /// Stops the renewal and waits for the task to exit.
+    ///
+    /// Returns an error if the task panicked before.
+    pub async fn shutdown(self) -> Result<(), NiocaError> {
+        self.shutdown.notify_one();
+        self.join
+            .await
+            .map_err(|err| NiocaError::TaskDied(err.to_string()))
+    }
+}
+
+/// The handle returned by all framework integrations, which combines the receiver for the
+/// converted certificates with the controls for the renewal task.
+#[derive(Debug)]
+pub struct RenewalHandle<T> {
+    rx: watch::Receiver<Option<T>>,
+    task: RenewalTask,
+}
+
+impl<T> RenewalHandle<T> {
+    pub fn new(rx: watch::Receiver<Option<T>>, task: RenewalTask) -> Self {
+        Self { rx, task }
+    }
+
+    /// Splits the handle into the receiver and the task controls.
+    pub fn into_parts(self) -> (watch::Receiver<Option<T>>, RenewalTask) {
+        (self.rx, self.task)
+    }
+
+    /// Returns a receiver, which always contains the latest certificate.
+    pub fn receiver(&self) -> watch::Receiver<Option<T>> {
+        self.rx.clone()
+    }
+
+    /// Returns a receiver for status updates of the renewal task.
+    pub fn status(&self) -> watch::Receiver<RenewalStatus> {
+        self.task.status()
+    }
+
+    /// Returns `false` if the renewal task has stopped, either after a shutdown or because
+    /// it panicked.
+    pub fn is_running(&self) -> bool {
+        self.task.is_running()
+    }
+
+    /// Stops the renewal and waits for the task to exit.
+    ///
+    /// Returns an error if the task panicked before.
+    pub async fn shutdown(self) -> Result<(), NiocaError> {
+        self.task.shutdown().await
+    }
+
+    /// Waits until the first certificate is available.
+    ///
+    /// Returns `NiocaError::Timeout` with the last renewal error, if there was one, when no
+    /// certificate arrived in time.
+    ///
+    /// The `spawn_and_wait` functions of all framework integrations use this through
+    /// [RenewalHandle::into_ready], which also stops the renewal on a timeout. This makes
+    /// sure that a server never starts without TLS material.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<(), NiocaError> {
+        let mut rx = self.rx.clone();
+        let wait = async {
+            loop {
+                if rx.borrow_and_update().is_some() {
+                    return Ok(());
+                }
+                if rx.changed().await.is_err() {
+                    return Err(NiocaError::TaskDied(
+                        "The renewal task exited before the first certificate".to_string(),
+                    ));
+                }
+            }
+        };
+
+        match time::timeout(timeout, wait).await {
+            Ok(res) => res,
+            Err(_) => {
+                let reason = match &*self.task.status.borrow() {
+                    RenewalStatus::Failed { error, .. } => error.to_string(),
+                    _ => "no response from Nioca".to_string(),
+                };
+                Err(NiocaError::Timeout(format!(
+                    "No certificate after {} seconds - last error: {}",
+                    timeout.as_secs(),
+                    reason
+                )))
+            }
+        }
+    }
+
+    /// Waits for the first certificate like [RenewalHandle::wait_ready] and shuts the renewal
+    /// task down, if it does not arrive within the given `timeout`.
+    pub async fn into_ready(self, timeout: Duration) -> Result<Self, NiocaError> {
+        match self.wait_ready(timeout).await {
+            Ok(_) => Ok(self),
+            Err(err) => {
+                let _ = self.shutdown().await;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Spawns the X509 renewal loop, which feeds every new certificate into the given `sink`.
+///
+/// Must be called from within a tokio runtime.
+pub fn spawn_renewal<S: CertSink>(
+    config: NiocaConfig,
+    mut sink: S,
+) -> Result<RenewalTask, NiocaError> {
+    let api_key = if let Some(key) = &config.api_key_x509 {
+        key.clone()
+    } else {
+        return Err(NiocaError::Config(
+            "NIOCA_X509_API_KEY is not set".to_string(),
+        ));
+    };
+    let url = if let Some(url) = &config.url_x509 {
+        url.to_string()
+    } else {
+        return Err(NiocaError::Config(
+            "NIOCA_X509_CLIENT_ID is not set".to_string(),
+        ));
+    };
+
+    let client = req_client(&config)?;
+    let (status_tx, status) = watch::channel(RenewalStatus::Starting);
+    let shutdown = Arc::new(Notify::new());
+    let shutdown_rx = shutdown.clone();
+
+    let join = tokio::spawn(async move {
+        let bearer = Secret::new(auth_token(api_key.expose_secret()));
+        let policy = config.renewal;
+        let mut failures = 0;
+
+        loop {
+            let res = tokio::select! {
+                _ = shutdown_rx.notified() => break,
+                res = fetch_cert_x509(&client, &url, bearer.expose_secret()) => res,
+            };
+            let res = res
+                .and_then(|certs| {
+                    certs.to_format(
+                        X509CertFormat::Pem,
+                        config.pkcs12_password.as_ref().map(Secret::expose_secret),
+                    )
+                })
+                .and_then(|certs| {
+                    let info = certs.validate(config.root_pem.as_deref(), config.clock_skew)?;
+                    Ok((certs, info))
+                });
+            let res = match res {
+                Ok((certs, info)) => sink.accept(&certs).await.map(|_| {
+                    info!("Renewed certificate {}", info);
+                    certs.not_after
+                }),
+                Err(err) => Err(err),
+            };
+
+            let sleep = match res {
+                Ok(not_after) => {
+                    failures = 0;
+                    let next_renewal = policy.next_renewal(not_after);
+                    status_tx.send_replace(RenewalStatus::Renewed {
+                        not_after,
+                        next_renewal,
+                    });
+                    next_renewal
+                }
+                Err(err) => {
+                    error!("{}", err);
+                    failures += 1;
+                    let retry_in = policy.next_retry(failures, err.retry_after());
+                    status_tx.send_replace(RenewalStatus::Failed {
+                        error: err,
+                        failures,
+                        retry_in,
+                    });
+                    retry_in
+                }
+            };
+
+            info!("Fetching next certificate in {} seconds", sleep.as_secs());
+            tokio::select! {
+                _ = shutdown_rx.notified() => break,
+                _ = time::sleep(sleep) => {},
+            }
+        }
+
+        info!("Certificate renewal has been shut down");
+        status_tx.send_replace(RenewalStatus::Stopped);
+    });
+
+    Ok(RenewalTask {
+        status,
+        shutdown,
+        join,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ca, leaf, mock_nioca};
+    use pretty_assertions::assert_eq;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the accepted certificates.
+    struct MockSink {
+        accepted: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl CertSink for MockSink {
+        async fn accept(&mut self, _certs: &CertX509Response) -> Result<(), NiocaError> {
+            self.accepted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Rejects all certificates.
+    struct RejectingSink;
+
+    #[async_trait]
+    impl CertSink for RejectingSink {
+        async fn accept(&mut self, _certs: &CertX509Response) -> Result<(), NiocaError> {
+            Err(NiocaError::InvalidCertificate(
+                "rejected by the test".to_string(),
+            ))
+        }
+    }
+
+    fn config(nioca_addr: SocketAddr, root_pem: String) -> NiocaConfig {
+        NiocaConfig::builder()
+            .url(format!("https://localhost:{}", nioca_addr.port()))
+            .client_id_x509("test")
+            .api_key_x509("secret")
+            .root_pem(root_pem)
+            .build()
+            .unwrap()
+    }
+
+    fn policy() -> RenewalPolicy {
+        RenewalPolicy {
+            renew_at: 0.9,
+            jitter: 0.0,
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(10_000),
+            backoff_initial: Duration::from_secs(10),
+            backoff_max: Duration::from_secs(100),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RenewalPolicy::default().validate().is_ok());
+        assert!(policy().validate().is_ok());
+
+        let invalid = [
+            RenewalPolicy {
+                renew_at: 0.0,
+                ..policy()
+            },
+            RenewalPolicy {
+                renew_at: 1.1,
+                ..policy()
+            },
+            RenewalPolicy {
+                renew_at: f64::NAN,
+                ..policy()
+            },
+            RenewalPolicy {
+                jitter: 1.0,
+                ..policy()
+            },
+            RenewalPolicy {
+                jitter: -0.1,
+                ..policy()
+            },
+            RenewalPolicy {
+                min_interval: Duration::from_secs(10_001),
+                ..policy()
+            },
+            RenewalPolicy {
+                backoff_initial: Duration::from_secs(101),
+                ..policy()
+            },
+        ];
+        for policy in invalid {
+            assert!(
+                matches!(policy.validate(), Err(NiocaError::Config(_))),
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn test_next_renewal() {
+        let policy = RenewalPolicy {
+            jitter: 0.1,
+            ..policy()
+        };
+        let not_after = Utc::now().timestamp() + 1000;
+        for _ in 0..100 {
+            // 900 seconds +-10%, with some room for a second passing in between
+            let secs = policy.next_renewal(not_after).as_secs_f64();
+            assert!((800.0..=990.0).contains(&secs), "{}", secs);
+        }
+
+        // already expired
+        let expired = Utc::now().timestamp() - 1000;
+        assert_eq!(policy.next_renewal(expired), policy.min_interval);
+        // valid for a very long time
+        let far = Utc::now().timestamp() + 1_000_000;
+        assert_eq!(policy.next_renewal(far), policy.max_interval);
+    }
+
+    #[test]
+    fn test_next_retry() {
+        let policy = policy();
+        let retries = (1..=6)
+            .map(|failures| policy.next_retry(failures, None).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(retries, vec![10, 20, 40, 80, 100, 100]);
+        assert_eq!(policy.next_retry(u32::MAX, None), policy.backoff_max);
+
+        // a longer retry_after from Nioca wins, a shorter one is ignored
+        let retry_after = Duration::from_secs(500);
+        assert_eq!(policy.next_retry(1, Some(retry_after)), retry_after);
+        assert_eq!(
+            policy.next_retry(3, Some(Duration::from_secs(5))),
+            Duration::from_secs(40)
+        );
+    }
+
+    #[test]
+    fn test_finalize() {
+        let policy = policy();
+        assert_eq!(policy.finalize(42.0), Duration::from_secs(42));
+        assert_eq!(policy.finalize(-5.0), policy.min_interval);
+        assert_eq!(policy.finalize(0.1), policy.min_interval);
+        assert_eq!(policy.finalize(1e9), policy.max_interval);
+
+        let policy = RenewalPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let secs = policy.finalize(100.0).as_secs_f64();
+            assert!((50.0..=150.0).contains(&secs), "{}", secs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let root = ca("Root");
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let addr = mock_nioca(&root, vec![leaf(&root, "Leaf")], fetches).await;
+
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let sink = MockSink {
+            accepted: accepted.clone(),
+        };
+        let task = spawn_renewal(config(addr, root.serialize_pem().unwrap()), sink).unwrap();
+        let mut status = task.status();
+        time::timeout(
+            Duration::from_secs(10),
+            status.wait_for(|status| matches!(status, RenewalStatus::Renewed { .. })),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(task.is_running());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // the task sleeps for at least `min_interval` now, which must not delay the shutdown
+        time::timeout(Duration::from_secs(1), task.shutdown())
+            .await
+            .expect("The renewal task did not exit in time")
+            .unwrap();
+        assert!(matches!(*status.borrow(), RenewalStatus::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready_timeout() {
+        let root = ca("Root");
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let addr = mock_nioca(&root, vec![leaf(&root, "Leaf")], fetches).await;
+
+        let task =
+            spawn_renewal(config(addr, root.serialize_pem().unwrap()), RejectingSink).unwrap();
+        let status = task.status();
+        let (_tx, rx) = watch::channel(None::<()>);
+        let handle = RenewalHandle::new(rx, task);
+
+        match handle.wait_ready(Duration::from_millis(500)).await {
+            Err(NiocaError::Timeout(msg)) => {
+                assert!(msg.contains("rejected by the test"), "{}", msg)
+            }
+            res => panic!("Expected a timeout, got: {:?}", res),
+        }
+        assert!(handle.is_running());
+
+        // into_ready stops the renewal on a timeout
+        let res = handle.into_ready(Duration::from_millis(100)).await;
+        assert!(matches!(res, Err(NiocaError::Timeout(_))));
+        assert!(matches!(*status.borrow(), RenewalStatus::Stopped));
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use zeroize::Zeroize;
+
+/// A string with secret content like a private key or an API key.
+///
+/// It is never printed by `Debug` and its memory is wiped on drop. It can be deserialized,
+/// but does not implement `Serialize`, so it is never written out along with a config or a
+/// response. Use [Secret::expose_secret] to access the value, which makes every access easy
+/// to find.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Returns the secret value.
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(<REDACTED>)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_redacted() {
+        let secret = Secret::from("super-secret-api-key");
+        assert_eq!(secret.expose_secret(), "super-secret-api-key");
+        assert!(!format!("{:?}", secret).contains("super-secret"));
+    }
+}
@@ -1,7 +1,12 @@
-use crate::ErrorResponse;
-use anyhow::Error;
+use crate::{error_from_response, NiocaError, Secret, ValidationError};
+use chrono::Utc;
 use reqwest::header::AUTHORIZATION;
 use serde::Deserialize;
+use ssh_key::certificate::CertType;
+use ssh_key::{Certificate, Fingerprint, HashAlg, PrivateKey, PublicKey};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,15 +25,143 @@ pub enum SshKeyAlg {
     Ed25519,
 }
 
+impl SshCertificateResponse {
+    /// Makes sure the certificate can be installed safely.
+    ///
+    /// - the certificate parses and is signed by one of the given CA fingerprints. The
+    ///   `user_ca_pub` from this response is only trusted, if no fingerprints are given and
+    ///   `trust_response_ca` is set, because the response would vouch for itself otherwise.
+    /// - the private key belongs to the certificate
+    /// - the certificate is valid right now, where a start of the validity up to `clock_skew`
+    ///   in the future is accepted
+    /// - the certificate contains at least one principal
+    /// - the certificate type matches `expected_type` and the type Nioca sent
+    ///
+    /// Fingerprints must be in the OpenSSH `SHA256:<base64>` format, like they are shown by
+    /// `ssh-keygen -l`. Returns the parsed certificate on success.
+    pub fn validate(
+        &self,
+        ca_fingerprints: &[String],
+        trust_response_ca: bool,
+        expected_type: Option<&SshCertType>,
+        clock_skew: Duration,
+    ) -> Result<Certificate, NiocaError> {
+        let cert = Certificate::from_openssh(&self.host_key_pair.id_pub).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot parse SSH certificate: {}", err))
+        })?;
+
+        let now = Utc::now().timestamp().max(0) as u64;
+        if now + clock_skew.as_secs() < cert.valid_after() {
+            return Err(ValidationError::NotYetValid(cert.valid_after() as i64).into());
+        }
+        if now >= cert.valid_before() {
+            return Err(ValidationError::Expired(cert.valid_before() as i64).into());
+        }
+
+        let trusted = if !ca_fingerprints.is_empty() {
+            ca_fingerprints
+                .iter()
+                .map(|fp| {
+                    Fingerprint::from_str(fp.trim()).map_err(|err| {
+                        NiocaError::Config(format!("Invalid SSH CA fingerprint '{}': {}", fp, err))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else if trust_response_ca {
+            let ca = PublicKey::from_openssh(&self.user_ca_pub).map_err(|err| {
+                NiocaError::InvalidCertificate(format!("Cannot parse the SSH CA key: {}", err))
+            })?;
+            vec![ca.fingerprint(HashAlg::Sha256)]
+        } else {
+            return Err(NiocaError::Config(
+                "No trusted SSH CA key - set NIOCA_SSH_CA_FINGERPRINTS or explicitly trust the CA \
+                key Nioca sends along with NIOCA_SSH_TRUST_RESPONSE_CA=true"
+                    .to_string(),
+            ));
+        };
+        let ca_fingerprint = cert.signature_key().fingerprint(HashAlg::Sha256);
+        if !trusted.contains(&ca_fingerprint) {
+            return Err(ValidationError::UntrustedCa(format!(
+                "{} is not an expected CA key",
+                ca_fingerprint
+            ))
+            .into());
+        }
+        // within the clock skew, the signature is checked at the start of the validity
+        cert.validate_at(now.max(cert.valid_after()), &trusted)
+            .map_err(|_| {
+                ValidationError::UntrustedCa("the signature does not match the CA key".to_string())
+            })?;
+
+        let key =
+            PrivateKey::from_openssh(self.host_key_pair.id.expose_secret()).map_err(|err| {
+                NiocaError::InvalidCertificate(format!("Cannot parse SSH private key: {}", err))
+            })?;
+        if key.public_key().key_data() != cert.public_key() {
+            return Err(ValidationError::KeyMismatch(
+                "the SSH certificate has been issued for another key".to_string(),
+            )
+            .into());
+        }
+
+        if cert.valid_principals().is_empty() {
+            return Err(ValidationError::NoPrincipals.into());
+        }
+
+        let actual = match cert.cert_type() {
+            CertType::Host => SshCertType::Host,
+            CertType::User => SshCertType::User,
+        };
+        for expected in [expected_type, self.host_key_pair.typ.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if expected != &actual {
+                return Err(ValidationError::CertTypeMismatch {
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                }
+                .into());
+            }
+        }
+
+        Ok(cert)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub enum SshCertType {
     Host,
     User,
 }
 
+impl Display for SshCertType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Host => write!(f, "host"),
+            Self::User => write!(f, "user"),
+        }
+    }
+}
+
+impl FromStr for SshCertType {
+    type Err = NiocaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "host" => Ok(Self::Host),
+            "user" => Ok(Self::User),
+            _ => Err(NiocaError::Config(format!(
+                "Unknown SSH certificate type '{}' - expected host or user",
+                s
+            ))),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SshKeyPairOpenssh {
-    pub id: String,
+    pub id: Secret,
     pub id_pub: String,
     pub alg: SshKeyAlg,
     pub typ: Option<SshCertType>,
@@ -38,49 +171,137 @@ pub async fn fetch_cert_ssh(
     client: &reqwest::Client,
     url: &str,
     bearer: &str,
-) -> anyhow::Result<SshCertificateResponse> {
-    match client
+) -> Result<SshCertificateResponse, NiocaError> {
+    let resp = client
         .post(url)
         .header(AUTHORIZATION, bearer)
         .header("Accept", "application/json")
         .send()
         .await
-    {
-        Ok(resp) => {
-            let status = resp.status();
-
-            if status.is_success() {
-                match resp.json::<SshCertificateResponse>().await {
-                    Ok(kp) => Ok(kp),
-                    Err(err) => {
-                        let msg = format!(
-                            "{} - Error deserializing response into SshCertificateResponse: {}",
-                            status, err
-                        );
-                        Err(Error::msg(msg))
-                    }
-                }
-            } else if status == reqwest::StatusCode::METHOD_NOT_ALLOWED {
-                let msg = r#"
-'405 Method Not Allowed' from Nioca Server.
-This usually happens if Nioca is sealed. Check and unseal if necessary."#;
-                Err(Error::msg(msg))
-            } else {
-                match resp.json::<ErrorResponse>().await {
-                    Ok(err) => Err(Error::msg(err.message)),
-                    Err(err) => {
-                        let msg = format!(
-                            "{} - Error deserializing response into ErrorResponse: {}",
-                            status, err
-                        );
-                        Err(Error::msg(msg))
-                    }
-                }
-            }
+        .map_err(|err| {
+            NiocaError::Transport(format!(
+                "Error fetching SSH certificate from Nioca: {}",
+                err
+            ))
+        })?;
+
+    let status = resp.status();
+    if status.is_success() {
+        resp.json::<SshCertificateResponse>().await.map_err(|err| {
+            NiocaError::Decode(format!(
+                "{} - Error deserializing response into SshCertificateResponse: {}",
+                status, err
+            ))
+        })
+    } else {
+        Err(error_from_response(resp).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use ssh_key::certificate::Builder;
+    use ssh_key::{Algorithm, LineEnding};
+
+    const SKEW: Duration = Duration::from_secs(60);
+
+    fn response(ca: &PrivateKey, typ: CertType, principal: Option<&str>) -> SshCertificateResponse {
+        let now = Utc::now().timestamp() as u64;
+        response_from(ca, typ, principal, now - 60)
+    }
+
+    fn response_from(
+        ca: &PrivateKey,
+        typ: CertType,
+        principal: Option<&str>,
+        valid_after: u64,
+    ) -> SshCertificateResponse {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let mut builder = Builder::new_with_random_nonce(
+            &mut OsRng,
+            key.public_key(),
+            valid_after,
+            valid_after + 3600,
+        )
+        .unwrap();
+        builder.cert_type(typ).unwrap();
+        match principal {
+            Some(principal) => builder.valid_principal(principal).unwrap(),
+            None => builder.all_principals_valid().unwrap(),
+        };
+        let cert = builder.sign(ca).unwrap();
+
+        SshCertificateResponse {
+            user_ca_pub: ca.public_key().to_openssh().unwrap(),
+            host_key_pair: SshKeyPairOpenssh {
+                id: key.to_openssh(LineEnding::LF).unwrap().as_str().into(),
+                id_pub: cert.to_openssh().unwrap(),
+                alg: SshKeyAlg::Ed25519,
+                typ: Some(SshCertType::Host),
+            },
         }
-        Err(err) => Err(Error::msg(format!(
-            "Error fetching SSH certificate from Nioca: {}",
-            err
-        ))),
+    }
+
+    #[test]
+    fn test_validate() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let resp = response(&ca, CertType::Host, Some("host.local"));
+        resp.validate(&[], true, Some(&SshCertType::Host), SKEW)
+            .unwrap();
+
+        let trusted = vec![ca.public_key().fingerprint(HashAlg::Sha256).to_string()];
+        resp.validate(&trusted, false, None, SKEW).unwrap();
+
+        // the CA key from the response itself is only trusted on request
+        assert!(matches!(
+            resp.validate(&[], false, None, SKEW),
+            Err(NiocaError::Config(_))
+        ));
+
+        let other_ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let other_fingerprint = other_ca
+            .public_key()
+            .fingerprint(HashAlg::Sha256)
+            .to_string();
+        assert!(matches!(
+            resp.validate(&[other_fingerprint], true, None, SKEW),
+            Err(NiocaError::Validation(ValidationError::UntrustedCa(_)))
+        ));
+
+        assert!(matches!(
+            resp.validate(&trusted, false, Some(&SshCertType::User), SKEW),
+            Err(NiocaError::Validation(
+                ValidationError::CertTypeMismatch { .. }
+            ))
+        ));
+
+        let mut wrong_key = resp.clone();
+        wrong_key.host_key_pair.id = other_ca.to_openssh(LineEnding::LF).unwrap().as_str().into();
+        assert!(matches!(
+            wrong_key.validate(&[], true, None, SKEW),
+            Err(NiocaError::Validation(ValidationError::KeyMismatch(_)))
+        ));
+
+        let no_principals = response(&ca, CertType::Host, None);
+        assert!(matches!(
+            no_principals.validate(&[], true, None, SKEW),
+            Err(NiocaError::Validation(ValidationError::NoPrincipals))
+        ));
+    }
+
+    #[test]
+    fn test_validate_clock_skew() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let now = Utc::now().timestamp() as u64;
+        let resp = response_from(&ca, CertType::Host, Some("host.local"), now + 30);
+
+        // issued a few seconds ahead of the local clock
+        resp.validate(&[], true, None, SKEW).unwrap();
+        assert!(matches!(
+            resp.validate(&[], true, None, Duration::from_secs(10)),
+            Err(NiocaError::Validation(ValidationError::NotYetValid(_)))
+        ));
     }
 }
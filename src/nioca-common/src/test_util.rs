@@ -0,0 +1,177 @@
+//! Certificate fixtures and a fake Nioca for the tests of this crate.
+
+use crate::fingerprint;
+use crate::tls::{der_to_pem, pem_to_certs, pem_to_private_key};
+use crate::x509::{CertX509Response, X509CertFormat};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use rustls::ServerConfig;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// A signed certificate with its key.
+///
+/// rcgen creates a new signature with each serialization, which is why all encodings are
+/// taken from a single DER.
+#[derive(Debug, Clone)]
+pub struct Leaf {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub der: Vec<u8>,
+}
+
+impl Leaf {
+    /// Signs the given certificate with `signer` or self-signs it, if there is none.
+    pub fn new(cert: &Certificate, signer: Option<&Certificate>) -> Self {
+        let der = match signer {
+            Some(signer) => cert.serialize_der_with_signer(signer).unwrap(),
+            None => cert.serialize_der().unwrap(),
+        };
+        Self {
+            cert_pem: der_to_pem("CERTIFICATE", &der),
+            key_pem: cert.serialize_private_key_pem(),
+            der,
+        }
+    }
+
+    /// Returns this certificate like Nioca delivers it in PEM format.
+    pub fn response(&self, cert_chain: impl Into<String>) -> CertX509Response {
+        CertX509Response {
+            cert: self.cert_pem.clone(),
+            cert_fingerprint: fingerprint(&self.der),
+            cert_chain: cert_chain.into(),
+            key: self.key_pem.clone().into(),
+            cert_format: X509CertFormat::Pem,
+            not_after: 0,
+        }
+    }
+}
+
+/// Returns a CA certificate with the given common name.
+pub fn ca(name: &str) -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+/// Returns an unsigned end-entity certificate with the given common name and SANs.
+pub fn cert(name: &str, sans: &[&str]) -> Certificate {
+    let sans = sans.iter().map(|san| san.to_string()).collect::<Vec<_>>();
+    let mut params = CertificateParams::new(sans);
+    params.distinguished_name.push(DnType::CommonName, name);
+    Certificate::from_params(params).unwrap()
+}
+
+/// Returns a certificate for `localhost` with the given common name, signed by `root`.
+pub fn leaf(root: &Certificate, name: &str) -> Leaf {
+    Leaf::new(&cert(name, &["localhost"]), Some(root))
+}
+
+/// Starts a fake Nioca on localhost, which answers each x509 fetch with the given leaves in
+/// order and repeats the last one. Each certificate is valid for 2 more seconds.
+///
+/// `fetches` counts the requests.
+pub async fn mock_nioca(
+    root: &Certificate,
+    leaves: Vec<Leaf>,
+    fetches: Arc<AtomicUsize>,
+) -> SocketAddr {
+    let server = leaf(root, "Nioca");
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            pem_to_certs(&server.cert_pem).unwrap(),
+            pem_to_private_key(&server.key_pem).unwrap(),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let root_pem = der_to_pem("CERTIFICATE", &root.serialize_der().unwrap());
+    let leaves = Arc::new(leaves);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let leaves = leaves.clone();
+            let fetches = fetches.clone();
+            let root_pem = root_pem.clone();
+
+            tokio::spawn(async move {
+                let mut tls = match acceptor.accept(stream).await {
+                    Ok(tls) => tls,
+                    Err(_) => return,
+                };
+                if read_request(&mut tls).await.is_none() {
+                    return;
+                }
+
+                let idx = fetches.fetch_add(1, Ordering::SeqCst).min(leaves.len() - 1);
+                let leaf = &leaves[idx];
+                let body = format!(
+                    r#"{{"cert":"{}","certFingerprint":"{}","certChain":"{}","key":"{}","certFormat":"PEM","notAfter":{}}}"#,
+                    json_escape(&leaf.cert_pem),
+                    fingerprint(&leaf.der),
+                    json_escape(&root_pem),
+                    json_escape(&leaf.key_pem),
+                    chrono::Utc::now().timestamp() + 2,
+                );
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = tls.write_all(resp.as_bytes()).await;
+                let _ = tls.shutdown().await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// Reads a single HTTP/1.1 request with its body and returns the head.
+async fn read_request<S: AsyncReadExt + Unpin>(stream: &mut S) -> Option<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() - head_end < content_length {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+
+    Some(head)
+}
+
+fn json_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
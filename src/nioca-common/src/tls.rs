@@ -0,0 +1,483 @@
+use crate::format::X509Bundle;
+use crate::x509::CertX509Response;
+use crate::{NiocaConfig, NiocaError};
+use arc_swap::ArcSwapOption;
+use base64::engine::general_purpose;
+use base64::Engine;
+use rustls::server::WantsServerCert;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    ClientHello, NoClientAuth, ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey, SigningKey};
+use rustls::{
+    Certificate, ConfigBuilder, PrivateKey, RootCertStore, ServerConfig, SignatureScheme,
+};
+use rustls_pemfile::Item;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// The schemes we offer when checking that a private key matches its certificate.
+/// At least one of them is supported by every key type rustls can handle.
+const KEY_CHECK_SCHEMES: [SignatureScheme; 4] = [
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// Parses all certificates from the given PEM, which may contain any number of blocks.
+pub fn pem_to_certs(pem: &str) -> Result<Vec<Certificate>, NiocaError> {
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes()).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Cannot parse PEM certificates: {}", err))
+    })?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Encodes DER data as a PEM block with the given label, like `CERTIFICATE`.
+pub fn der_to_pem(label: &str, der: &[u8]) -> String {
+    let b64 = general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in b64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is always valid UTF-8"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Encodes all given certificates as concatenated PEM blocks.
+pub fn certs_to_pem(certs: &[Certificate]) -> String {
+    certs
+        .iter()
+        .map(|cert| der_to_pem("CERTIFICATE", &cert.0))
+        .collect()
+}
+
+/// Parses the first private key from the given PEM. PKCS8, PKCS1 (RSA) and SEC1 (EC) keys
+/// are supported.
+pub fn pem_to_private_key(pem: &str) -> Result<PrivateKey, NiocaError> {
+    let mut rd = pem.as_bytes();
+    loop {
+        match rustls_pemfile::read_one(&mut rd) {
+            Ok(Some(Item::PKCS8Key(key)))
+            | Ok(Some(Item::RSAKey(key)))
+            | Ok(Some(Item::ECKey(key))) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return Err(NiocaError::InvalidCertificate(
+                    "No private key found in the given PEM".to_string(),
+                ))
+            }
+            Err(err) => {
+                return Err(NiocaError::InvalidCertificate(format!(
+                    "Cannot parse PEM private key: {}",
+                    err
+                )))
+            }
+        }
+    }
+}
+
+/// Brings the given certificates into the order rustls expects: the leaf first, followed by
+/// each certificate which issued the one before.
+///
+/// Certificates which do not belong to the chain of the leaf are appended at the end in
+/// their original order. Duplicates are removed.
+pub fn order_chain(
+    leaf: Certificate,
+    others: Vec<Certificate>,
+) -> Result<Vec<Certificate>, NiocaError> {
+    let mut pool: Vec<Certificate> = Vec::with_capacity(others.len());
+    for cert in others {
+        if cert != leaf && !pool.contains(&cert) {
+            pool.push(cert);
+        }
+    }
+
+    let mut chain = vec![leaf];
+    loop {
+        let last = chain.last().expect("chain always contains the leaf");
+        let (_, last) = X509Certificate::from_der(&last.0).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot parse certificate: {}", err))
+        })?;
+        if last.subject().as_raw() == last.issuer().as_raw() {
+            // self-signed root -> the chain is complete
+            break;
+        }
+        let issuer = last.issuer().as_raw().to_vec();
+
+        let pos = pool.iter().position(|cert| {
+            X509Certificate::from_der(&cert.0)
+                .map(|(_, c)| c.subject().as_raw() == issuer.as_slice())
+                .unwrap_or(false)
+        });
+        match pos {
+            Some(pos) => chain.push(pool.remove(pos)),
+            None => break,
+        }
+    }
+
+    chain.append(&mut pool);
+    Ok(chain)
+}
+
+/// Checks that the given private key belongs to the given certificate by signing a test
+/// message and verifying it with the certificates public key.
+pub fn verify_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), NiocaError> {
+    let signing_key = any_supported_type(key).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Unsupported private key: {}", err))
+    })?;
+    verify_signing_key_matches(cert, signing_key.as_ref())
+}
+
+fn verify_signing_key_matches(cert: &Certificate, key: &dyn SigningKey) -> Result<(), NiocaError> {
+    let signer = key.choose_scheme(&KEY_CHECK_SCHEMES).ok_or_else(|| {
+        NiocaError::InvalidCertificate("Unsupported private key algorithm".to_string())
+    })?;
+    let alg = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+
+    let msg = b"nioca-client key check";
+    let sig = signer.sign(msg).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Cannot sign with private key: {}", err))
+    })?;
+
+    let ee = webpki::EndEntityCert::try_from(cert.0.as_slice()).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Cannot parse leaf certificate: {:?}", err))
+    })?;
+    ee.verify_signature(alg, msg, &sig).map_err(|_| {
+        NiocaError::InvalidCertificate(
+            "The private key does not match the leaf certificate".to_string(),
+        )
+    })
+}
+
+/// Parses the certificate chain and private key from a `CertX509Response`.
+///
+/// All certificates from `cert` and `cert_chain` are used, the chain is ordered leaf first and
+/// the private key is checked to belong to the leaf. The key is always returned as PKCS8.
+///
+/// PEM and DER responses are decoded directly. Password protected PKCS12 responses must be
+/// converted with [CertX509Response::to_format] first.
+pub fn server_cert_and_key(
+    certs: &CertX509Response,
+) -> Result<(Vec<Certificate>, PrivateKey), NiocaError> {
+    let bundle = X509Bundle::from_response(certs, None)?;
+    let chain = order_chain(bundle.leaf().clone(), bundle.chain[1..].to_vec())?;
+    verify_key_matches(&chain[0], &bundle.key)?;
+
+    Ok((chain, bundle.key.clone()))
+}
+
+/// Builds a rustls `CertifiedKey` from a `CertX509Response` with the same checks as
+/// [server_cert_and_key].
+pub fn certified_key(certs: &CertX509Response) -> Result<rustls::sign::CertifiedKey, NiocaError> {
+    let (chain, key) = server_cert_and_key(certs)?;
+    let signing_key: Arc<dyn SigningKey> = any_supported_type(&key).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Unsupported private key: {}", err))
+    })?;
+    Ok(rustls::sign::CertifiedKey::new(chain, signing_key))
+}
+
+/// A rustls `ResolvesServerCert` which always serves the latest Nioca certificate.
+///
+/// The renewal loop swaps the certificate in place, which means that a `ServerConfig` built
+/// with this resolver never needs to be rebuilt or re-bound. Until the first certificate has
+/// been set, all handshakes will fail.
+#[derive(Default)]
+pub struct NiocaCertResolver {
+    current: ArcSwapOption<CertifiedKey>,
+}
+
+impl NiocaCertResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validates the given certificate and makes it the active one. On error, the currently
+    /// active certificate is kept.
+    pub fn update(&self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        let key = certified_key(certs)?;
+        self.current.store(Some(Arc::new(key)));
+        Ok(())
+    }
+
+    /// Returns the currently active certificate, if any.
+    pub fn current(&self) -> Option<Arc<CertifiedKey>> {
+        self.current.load_full()
+    }
+
+    /// Returns `true` as soon as the first certificate has been set.
+    pub fn is_ready(&self) -> bool {
+        self.current.load().is_some()
+    }
+}
+
+impl ResolvesServerCert for NiocaCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.load_full()
+    }
+}
+
+/// Whether servers built by the framework integrations ask connecting clients for a
+/// certificate issued by the Nioca root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Plain server side TLS (default)
+    #[default]
+    None,
+    /// Clients may present a certificate. If they do, it must chain up to the Nioca root.
+    Optional,
+    /// Clients must present a certificate, which chains up to the Nioca root.
+    Required,
+}
+
+impl FromStr for ClientAuth {
+    type Err = NiocaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "" => Ok(Self::None),
+            "optional" => Ok(Self::Optional),
+            "required" => Ok(Self::Required),
+            _ => Err(NiocaError::Config(format!(
+                "Invalid client auth '{}' - expected one of: none, optional, required",
+                s
+            ))),
+        }
+    }
+}
+
+/// Builds the client certificate verifier for the given mode, which trusts only the given
+/// root PEM.
+pub fn client_cert_verifier(
+    client_auth: ClientAuth,
+    root_pem: Option<&str>,
+) -> Result<Arc<dyn ClientCertVerifier>, NiocaError> {
+    if client_auth == ClientAuth::None {
+        return Ok(NoClientAuth::boxed());
+    }
+
+    let root_pem = root_pem.ok_or_else(|| {
+        NiocaError::Config("Client certificate authentication requires a root PEM".to_string())
+    })?;
+    let mut roots = RootCertStore::empty();
+    for cert in pem_to_certs(root_pem)? {
+        roots.add(&cert).map_err(|err| {
+            NiocaError::Config(format!(
+                "Cannot add root certificate to trust store: {}",
+                err
+            ))
+        })?;
+    }
+    if roots.is_empty() {
+        return Err(NiocaError::Config(
+            "No certificate found in the root PEM".to_string(),
+        ));
+    }
+
+    let verifier = match client_auth {
+        ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+        _ => AllowAnyAuthenticatedClient::new(roots).boxed(),
+    };
+    Ok(verifier)
+}
+
+/// Returns a `ServerConfig` builder with safe defaults and client authentication as
+/// configured with `NiocaConfig::client_auth`.
+pub fn server_config_builder(
+    config: &NiocaConfig,
+) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, NiocaError> {
+    let verifier = client_cert_verifier(config.client_auth, config.root_pem.as_deref())?;
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier))
+}
+
+/// Builds a rustls `ServerConfig` with safe defaults, which takes its certificates from the
+/// given resolver.
+pub fn resolver_server_config(
+    config: &NiocaConfig,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<ServerConfig, NiocaError> {
+    Ok(server_config_builder(config)?.with_cert_resolver(resolver))
+}
+
+/// The identity of a client, which authenticated with a certificate during the handshake.
+///
+/// Only certificates which have been verified against the Nioca root ever end up here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The subject DN, for instance `CN=my-service, O=Nioca`
+    pub subject: String,
+    /// DNS names, IP addresses, emails and URIs from the subject alternative names
+    pub sans: Vec<String>,
+    /// The DER encoded leaf certificate
+    pub der: Vec<u8>,
+}
+
+impl PeerIdentity {
+    /// Parses the identity from a DER encoded certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self, NiocaError> {
+        let (_, cert) = X509Certificate::from_der(der).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot parse peer certificate: {}", err))
+        })?;
+
+        let sans = subject_alt_names(&cert);
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            sans,
+            der: der.to_vec(),
+        })
+    }
+
+    /// Extracts the identity from the peer certificates of an established rustls connection.
+    ///
+    /// Returns `None` if the client did not present a certificate.
+    pub fn from_peer_certificates(certs: Option<&[Certificate]>) -> Option<Self> {
+        let leaf = certs?.first()?;
+        Self::from_der(&leaf.0).ok()
+    }
+}
+
+/// Returns all DNS, email, URI and IP subject alternative names of the given certificate.
+pub(crate) fn subject_alt_names(cert: &X509Certificate<'_>) -> Vec<String> {
+    let mut sans = Vec::new();
+    if let Ok(Some(ext)) = cert.subject_alternative_name() {
+        for name in &ext.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => sans.push(name.to_string()),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                        _ => None,
+                    };
+                    if let Some(ip) = ip {
+                        sans.push(ip.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    sans
+}
+
+/// Returns only the DNS names from the subject alternative names of a DER encoded
+/// certificate, which are the ones a TLS server is selected by.
+pub fn dns_names(der: &[u8]) -> Result<Vec<String>, NiocaError> {
+    let (_, cert) = X509Certificate::from_der(der).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Cannot parse certificate: {}", err))
+    })?;
+
+    let mut names = Vec::new();
+    if let Ok(Some(ext)) = cert.subject_alternative_name() {
+        for name in &ext.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ca, cert};
+    use crate::x509::X509CertFormat;
+    use pretty_assertions::assert_eq;
+    use rcgen::Certificate as RcCert;
+
+    /// Returns (root, intermediate 1, intermediate 2, leaf)
+    fn chain() -> (RcCert, RcCert, RcCert, RcCert) {
+        (
+            ca("Root"),
+            ca("Intermediate 1"),
+            ca("Intermediate 2"),
+            cert("Leaf", &["localhost"]),
+        )
+    }
+
+    #[test]
+    fn test_multi_level_chain_is_ordered_leaf_first() {
+        let (root, int1, int2, leaf) = chain();
+        let root_pem = root.serialize_pem().unwrap();
+        let int1_pem = int1.serialize_pem_with_signer(&root).unwrap();
+        let int2_pem = int2.serialize_pem_with_signer(&int1).unwrap();
+        let leaf_pem = leaf.serialize_pem_with_signer(&int2).unwrap();
+
+        // the chain is delivered out of order on purpose
+        let certs = CertX509Response {
+            cert: leaf_pem.clone(),
+            cert_fingerprint: String::default(),
+            cert_chain: format!("{}{}{}", root_pem, int2_pem, int1_pem),
+            key: leaf.serialize_private_key_pem().into(),
+            cert_format: X509CertFormat::Pem,
+            not_after: 0,
+        };
+
+        let (chain, _key) = server_cert_and_key(&certs).unwrap();
+        let expected = [leaf_pem, int2_pem, int1_pem, root_pem]
+            .iter()
+            .map(|pem| pem_to_certs(pem).unwrap().remove(0))
+            .collect::<Vec<_>>();
+        assert_eq!(chain, expected);
+    }
+
+    #[test]
+    fn test_chain_without_root_and_duplicate_leaf() {
+        let (root, int1, int2, leaf) = chain();
+        let int1_pem = int1.serialize_pem_with_signer(&root).unwrap();
+        let int2_pem = int2.serialize_pem_with_signer(&int1).unwrap();
+        let leaf_pem = leaf.serialize_pem_with_signer(&int2).unwrap();
+
+        // some CAs deliver a full chain including the leaf
+        let certs = CertX509Response {
+            cert: leaf_pem.clone(),
+            cert_fingerprint: String::default(),
+            cert_chain: format!("{}{}{}", leaf_pem, int1_pem, int2_pem),
+            key: leaf.serialize_private_key_pem().into(),
+            cert_format: X509CertFormat::Pem,
+            not_after: 0,
+        };
+
+        let (chain, _key) = server_cert_and_key(&certs).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[1], pem_to_certs(&int2_pem).unwrap().remove(0));
+        assert_eq!(chain[2], pem_to_certs(&int1_pem).unwrap().remove(0));
+    }
+
+    #[test]
+    fn test_key_mismatch_is_rejected() {
+        let (root, int1, _, leaf) = chain();
+        let int1_pem = int1.serialize_pem_with_signer(&root).unwrap();
+        let leaf_pem = leaf.serialize_pem_with_signer(&int1).unwrap();
+
+        let certs = CertX509Response {
+            cert: leaf_pem,
+            cert_fingerprint: String::default(),
+            cert_chain: int1_pem,
+            key: int1.serialize_private_key_pem().into(),
+            cert_format: X509CertFormat::Pem,
+            not_after: 0,
+        };
+
+        assert!(matches!(
+            server_cert_and_key(&certs),
+            Err(NiocaError::InvalidCertificate(_))
+        ));
+    }
+}
@@ -1,33 +1,255 @@
-use anyhow::Error;
-use chrono::Utc;
+use crate::format::X509Bundle;
+use crate::pinning::normalize_fingerprint;
+use crate::tls::{pem_to_certs, subject_alt_names, verify_key_matches};
+use crate::{error_from_response, fingerprint, NiocaError, Secret, ValidationError};
+use chrono::{TimeZone, Utc};
 use reqwest::header::AUTHORIZATION;
+use rustls::Certificate;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use webpki::KeyUsage;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::public_key::PublicKey;
 
-#[derive(Debug, Clone, Serialize)]
-pub(crate) struct CertX509 {
-    pub cert: String,
-    pub cert_fingerprint: String,
-    pub cert_chain: String,
-    pub key: String,
-    pub cert_format: X509CertFormat,
-    /// not after as a unix timestamp in UTC format
-    pub not_after: i64,
-}
+static SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
-#[allow(dead_code)]
 pub struct CertX509Response {
     pub cert: String,
     pub cert_fingerprint: String,
     pub cert_chain: String,
-    pub key: String,
+    pub key: Secret,
     pub cert_format: X509CertFormat,
     /// not after as a unix timestamp in UTC format
     pub not_after: i64,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl CertX509Response {
+    /// Converts the certificate into the given format.
+    ///
+    /// `pkcs12_password` is used to decrypt a PKCS12 response as well as to encrypt the
+    /// output, if `format` is `PKCS12` (default: empty). During a conversion, PKCS1 (RSA) and
+    /// SEC1 (EC) keys are converted to PKCS8, while PKCS8 keys are kept as they are. If the
+    /// response already has the requested format, it is returned unchanged including its key,
+    /// apart from PKCS12, which is always re-encrypted with the given password.
+    pub fn to_format(
+        &self,
+        format: X509CertFormat,
+        pkcs12_password: Option<&str>,
+    ) -> Result<Self, NiocaError> {
+        if self.cert_format == format && format != X509CertFormat::PKCS12 {
+            return Ok(self.clone());
+        }
+        X509Bundle::from_response(self, pkcs12_password)?.to_response(format, pkcs12_password, self)
+    }
+
+    /// Parses the metadata of the leaf certificate and checks it against `cert_fingerprint`.
+    ///
+    /// Works for PEM and DER. Password protected PKCS12 responses must be converted with
+    /// [CertX509Response::to_format] first.
+    pub fn info(&self) -> Result<CertInfo, NiocaError> {
+        let bundle = X509Bundle::from_response(self, None)?;
+        self.leaf_info(&bundle)
+    }
+
+    /// Makes sure the certificate can be used before it is published or written to disk.
+    ///
+    /// - `cert_fingerprint` must match the leaf
+    /// - the leaf must be valid right now, where a start of the validity up to `clock_skew` in
+    ///   the future is accepted
+    /// - the private key must belong to the leaf
+    /// - the chain must lead up to the given Nioca root. This check is skipped without a root.
+    ///
+    /// Returns the parsed metadata of the leaf on success. Works for PEM and DER, password
+    /// protected PKCS12 responses must be converted with [CertX509Response::to_format] first.
+    pub fn validate(
+        &self,
+        root_pem: Option<&str>,
+        clock_skew: Duration,
+    ) -> Result<CertInfo, NiocaError> {
+        let bundle = X509Bundle::from_response(self, None)?;
+        let info = self.leaf_info(&bundle)?;
+
+        if !info.fingerprint_verified {
+            return Err(ValidationError::FingerprintMismatch {
+                expected: self.cert_fingerprint.clone(),
+                actual: info.fingerprint,
+            }
+            .into());
+        }
+
+        let now = Utc::now().timestamp();
+        if now + (clock_skew.as_secs() as i64) < info.not_before {
+            return Err(ValidationError::NotYetValid(info.not_before).into());
+        }
+        if now > info.not_after {
+            return Err(ValidationError::Expired(info.not_after).into());
+        }
+
+        verify_key_matches(bundle.leaf(), &bundle.key)
+            .map_err(|err| ValidationError::KeyMismatch(err.to_string()))?;
+
+        if let Some(root_pem) = root_pem {
+            // within the clock skew, the chain is checked at the start of the leaf's validity
+            verify_chain(&bundle.chain, root_pem, now.max(info.not_before))?;
+        }
+
+        Ok(info)
+    }
+
+    fn leaf_info(&self, bundle: &X509Bundle) -> Result<CertInfo, NiocaError> {
+        let mut info = CertInfo::from_der(&bundle.leaf().0)?;
+
+        // Nioca may hash either the DER certificate or the PEM it delivers
+        info.fingerprint_verified = match normalize_fingerprint(&self.cert_fingerprint) {
+            Ok(expected) => {
+                expected == info.fingerprint || expected == fingerprint(self.cert.as_bytes())
+            }
+            Err(_) => false,
+        };
+
+        Ok(info)
+    }
+}
+
+/// Verifies that the leaf, which is the first certificate in `chain`, has been issued by one
+/// of the certificates in `root_pem`, either directly or through the rest of the chain.
+fn verify_chain(chain: &[Certificate], root_pem: &str, at: i64) -> Result<(), NiocaError> {
+    let roots = pem_to_certs(root_pem)?;
+    let anchors = roots
+        .iter()
+        .map(|root| webpki::TrustAnchor::try_from_cert_der(&root.0))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot parse root certificate: {:?}", err))
+        })?;
+    let intermediates = chain[1..]
+        .iter()
+        .map(|cert| cert.0.as_slice())
+        .collect::<Vec<_>>();
+
+    let ee = webpki::EndEntityCert::try_from(chain[0].0.as_slice()).map_err(|err| {
+        NiocaError::InvalidCertificate(format!("Cannot parse leaf certificate: {:?}", err))
+    })?;
+    let time = webpki::Time::from_seconds_since_unix_epoch(at.max(0) as u64);
+
+    // Nioca certificates may be meant for servers, for clients or both
+    let res = ee
+        .verify_for_usage(
+            SIG_ALGS,
+            &anchors,
+            &intermediates,
+            time,
+            KeyUsage::server_auth(),
+            &[],
+        )
+        .or_else(|_| {
+            ee.verify_for_usage(
+                SIG_ALGS,
+                &anchors,
+                &intermediates,
+                time,
+                KeyUsage::client_auth(),
+                &[],
+            )
+        });
+    res.map_err(|err| ValidationError::UntrustedChain(format!("{:?}", err)).into())
+}
+
+/// The parsed metadata of a X509 certificate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    /// All DNS, email, URI and IP subject alternative names
+    pub sans: Vec<String>,
+    /// The serial number as colon separated hex
+    pub serial: String,
+    /// not before as a unix timestamp in UTC format
+    pub not_before: i64,
+    /// not after as a unix timestamp in UTC format
+    pub not_after: i64,
+    /// The public key algorithm, like `RSA 2048`, `EC P-256` or `Ed25519`
+    pub key_algorithm: String,
+    /// The SHA256 fingerprint of the DER certificate in the `sha256:<hex>` format
+    pub fingerprint: String,
+    /// `true` if the `cert_fingerprint` Nioca sent matches the certificate. Always `false`
+    /// for infos created with [CertInfo::from_der].
+    pub fingerprint_verified: bool,
+}
+
+impl CertInfo {
+    /// Parses the metadata from a DER encoded certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self, NiocaError> {
+        let (_, cert) = X509Certificate::from_der(der).map_err(|err| {
+            NiocaError::InvalidCertificate(format!("Cannot parse certificate: {}", err))
+        })?;
+
+        let spki = cert.public_key();
+        let key_algorithm = match spki.parsed() {
+            Ok(PublicKey::RSA(key)) => format!("RSA {}", key.key_size()),
+            Ok(PublicKey::EC(key)) => format!("EC P-{}", key.key_size()),
+            _ if spki.algorithm.algorithm == OID_SIG_ED25519 => "Ed25519".to_string(),
+            _ => spki.algorithm.algorithm.to_id_string(),
+        };
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            sans: subject_alt_names(&cert),
+            serial: cert.raw_serial_as_string(),
+            not_before: cert.validity().not_before.timestamp(),
+            not_after: cert.validity().not_after.timestamp(),
+            key_algorithm,
+            fingerprint: fingerprint(der),
+            fingerprint_verified: false,
+        })
+    }
+
+    /// Returns `true` if the certificate is valid at the given unix timestamp.
+    pub fn is_valid_at(&self, timestamp: i64) -> bool {
+        self.not_before <= timestamp && timestamp <= self.not_after
+    }
+}
+
+impl Display for CertInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fmt_ts = |ts: i64| match Utc.timestamp_opt(ts, 0).single() {
+            Some(dt) => dt.to_rfc3339(),
+            None => ts.to_string(),
+        };
+
+        write!(
+            f,
+            "{} [{}] issued by {} - serial: {}, key: {}, valid from {} until {}",
+            self.subject,
+            self.sans.join(", "),
+            self.issuer,
+            self.serial,
+            self.key_algorithm,
+            fmt_ts(self.not_before),
+            fmt_ts(self.not_after),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum X509CertFormat {
     Pem,
@@ -35,40 +257,122 @@ pub enum X509CertFormat {
     PKCS12,
 }
 
+impl FromStr for X509CertFormat {
+    type Err = NiocaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pem" => Ok(Self::Pem),
+            "der" => Ok(Self::Der),
+            "pkcs12" | "p12" => Ok(Self::PKCS12),
+            _ => Err(NiocaError::Config(format!(
+                "Unknown X509 certificate format '{}' - expected pem, der or pkcs12",
+                s
+            ))),
+        }
+    }
+}
+
 pub async fn fetch_cert_x509(
     client: &reqwest::Client,
     url: &str,
     bearer: &str,
-) -> anyhow::Result<(CertX509Response, u64)> {
-    match client
+) -> Result<CertX509Response, NiocaError> {
+    let resp = client
         .post(url)
         .header(AUTHORIZATION, bearer)
         .header("Accept", "application/json")
         .send()
         .await
-    {
-        Ok(resp) => {
-            let status = resp.status();
-            match resp.json::<CertX509Response>().await {
-                Ok(certs) => {
-                    // Nioca returns the not_after in seconds
-                    let now = Utc::now().timestamp();
-                    let diff = certs.not_after.saturating_sub(now);
-                    let renew = diff as u64 * 90 / 100;
-                    Ok((certs, renew))
-                }
-                Err(err) => {
-                    let msg = format!(
-                        "{} - Error deserializing response into CertX509Response: {}",
-                        status, err
-                    );
-                    Err(Error::msg(msg))
-                }
-            }
-        }
-        Err(err) => Err(Error::msg(format!(
-            "Error fetching TLS certificate from Nioca: {}",
-            err
-        ))),
+        .map_err(|err| {
+            NiocaError::Transport(format!(
+                "Error fetching TLS certificate from Nioca: {}",
+                err
+            ))
+        })?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(error_from_response(resp).await);
+    }
+
+    resp.json::<CertX509Response>().await.map_err(|err| {
+        NiocaError::Decode(format!(
+            "{} - Error deserializing response into CertX509Response: {}",
+            status, err
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ca, cert, leaf, Leaf};
+    use pretty_assertions::assert_eq;
+    use rcgen::CertificateParams;
+    use time::OffsetDateTime;
+
+    const SKEW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_cert_info() {
+        let mut certs = Leaf::new(&cert("Leaf", &["localhost", "127.0.0.1"]), None).response("");
+        let info = certs.info().unwrap();
+        assert_eq!(info.subject, "CN=Leaf");
+        assert_eq!(info.issuer, "CN=Leaf");
+        assert_eq!(info.sans, vec!["localhost", "127.0.0.1"]);
+        assert_eq!(info.key_algorithm, "EC P-256");
+        assert!(info.fingerprint_verified);
+        assert!(info.is_valid_at(Utc::now().timestamp()));
+
+        certs.cert_fingerprint = "sha256:1234".to_string();
+        assert!(!certs.info().unwrap().fingerprint_verified);
+    }
+
+    #[test]
+    fn test_validate() {
+        let root = ca("Root");
+        let root_pem = root.serialize_pem().unwrap();
+        let certs = leaf(&root, "Leaf").response(root_pem.clone());
+        certs.validate(Some(&root_pem), SKEW).unwrap();
+
+        let other_root = ca("Other Root").serialize_pem().unwrap();
+        assert!(matches!(
+            certs.validate(Some(&other_root), SKEW),
+            Err(NiocaError::Validation(ValidationError::UntrustedChain(_)))
+        ));
+
+        let mut wrong_key = certs.clone();
+        wrong_key.key = root.serialize_private_key_pem().into();
+        assert!(matches!(
+            wrong_key.validate(Some(&root_pem), SKEW),
+            Err(NiocaError::Validation(ValidationError::KeyMismatch(_)))
+        ));
+
+        let mut wrong_fingerprint = certs;
+        wrong_fingerprint.cert_fingerprint = fingerprint(b"something else");
+        assert!(matches!(
+            wrong_fingerprint.validate(Some(&root_pem), SKEW),
+            Err(NiocaError::Validation(
+                ValidationError::FingerprintMismatch { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_clock_skew() {
+        let root = ca("Root");
+        let root_pem = root.serialize_pem().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.not_before = OffsetDateTime::now_utc() + time::Duration::seconds(30);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let certs = Leaf::new(&cert, Some(&root)).response(root_pem.clone());
+
+        // issued a few seconds ahead of the local clock
+        certs.validate(Some(&root_pem), SKEW).unwrap();
+        assert!(matches!(
+            certs.validate(Some(&root_pem), Duration::from_secs(10)),
+            Err(NiocaError::Validation(ValidationError::NotYetValid(_)))
+        ));
     }
 }
@@ -0,0 +1,441 @@
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use nioca_common::tls::{certified_key, dns_names, resolver_server_config};
+use nioca_common::{spawn_renewal, CertSink};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub use nioca_common::tls::NiocaCertResolver;
+pub use nioca_common::x509::CertX509Response;
+pub use nioca_common::{
+    ClientAuth, NiocaConfig, NiocaError, PeerIdentity, RenewalHandle, RenewalStatus,
+};
+
+pub struct NiocaRustls;
+
+/// Swaps each renewed certificate into the resolver and publishes the acceptor once the
+/// first certificate is available.
+struct Sink {
+    resolver: Arc<NiocaCertResolver>,
+    acceptor: Option<TlsAcceptor>,
+    tx: watch::Sender<Option<TlsAcceptor>>,
+}
+
+#[async_trait]
+impl CertSink for Sink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        self.resolver.update(certs)?;
+        if let Some(acceptor) = self.acceptor.take() {
+            self.tx.send_replace(Some(acceptor));
+        }
+        Ok(())
+    }
+}
+
+/// Only keeps the resolver up to date for [NiocaRustls::spawn_with_resolver].
+struct ResolverSink {
+    resolver: Arc<NiocaCertResolver>,
+    tx: watch::Sender<Option<()>>,
+}
+
+#[async_trait]
+impl CertSink for ResolverSink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        self.resolver.update(certs)?;
+        self.tx.send_replace(Some(()));
+        Ok(())
+    }
+}
+
+/// Swaps each renewed certificate into its slot of the SNI resolver and publishes the
+/// names it is served for.
+struct SniSink {
+    resolver: Arc<NiocaSniResolver>,
+    idx: usize,
+    tx: watch::Sender<Option<Vec<String>>>,
+}
+
+#[async_trait]
+impl CertSink for SniSink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        let names = self.resolver.update(self.idx, certs)?;
+        self.tx.send_replace(Some(names));
+        Ok(())
+    }
+}
+
+impl NiocaRustls {
+    /// Spawns the certificate renewal.
+    ///
+    /// The `TlsAcceptor` is published once, as soon as the first certificate is available.
+    /// It is backed by a [NiocaCertResolver], which is updated in place with each renewal,
+    /// so every new connection gets the latest certificate.
+    ///
+    /// No ALPN protocols are configured. Use [NiocaRustls::spawn_with_resolver] together with
+    /// [NiocaRustls::server_config] to build a customized acceptor.
+    pub async fn spawn(config: NiocaConfig) -> Result<RenewalHandle<TlsAcceptor>, NiocaError> {
+        let resolver = Arc::new(NiocaCertResolver::new());
+        let server_config = Self::server_config(&config, resolver.clone())?;
+        let acceptor = Some(TlsAcceptor::from(Arc::new(server_config)));
+
+        let (tx, rx) = watch::channel(None);
+        let sink = Sink {
+            resolver,
+            acceptor,
+            tx,
+        };
+        let task = spawn_renewal(config, sink)?;
+        Ok(RenewalHandle::new(rx, task))
+    }
+
+    /// Spawns the renewal and waits for the first certificate, see
+    /// [RenewalHandle::wait_ready].
+    pub async fn spawn_and_wait(
+        config: NiocaConfig,
+        timeout: Duration,
+    ) -> Result<RenewalHandle<TlsAcceptor>, NiocaError> {
+        Self::spawn(config).await?.into_ready(timeout).await
+    }
+
+    /// Spawns the renewal, which keeps the given resolver up to date. The receiver contains
+    /// `Some(())` as soon as the first certificate has been set.
+    pub async fn spawn_with_resolver(
+        config: NiocaConfig,
+        resolver: Arc<NiocaCertResolver>,
+    ) -> Result<RenewalHandle<()>, NiocaError> {
+        let (tx, rx) = watch::channel(None);
+        let task = spawn_renewal(config, ResolverSink { resolver, tx })?;
+        Ok(RenewalHandle::new(rx, task))
+    }
+
+    /// Spawns one renewal per given config, which all feed a single [NiocaSniResolver].
+    ///
+    /// Each client is selected by SNI with the DNS names from its certificate. Connections
+    /// without SNI or with an unknown name get the certificate of the first config. Client
+    /// authentication is taken from the first config as well.
+    pub async fn spawn_sni(configs: Vec<NiocaConfig>) -> Result<NiocaSniHandle, NiocaError> {
+        let first = configs.first().ok_or_else(|| {
+            NiocaError::Config("At least one NiocaConfig is needed for SNI".to_string())
+        })?;
+        let resolver = Arc::new(NiocaSniResolver::new(configs.len()));
+        let server_config = Self::server_config(first, resolver.clone())?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let mut clients = Vec::with_capacity(configs.len());
+        for (idx, config) in configs.into_iter().enumerate() {
+            let (tx, rx) = watch::channel(None);
+            let sink = SniSink {
+                resolver: resolver.clone(),
+                idx,
+                tx,
+            };
+            match spawn_renewal(config, sink) {
+                Ok(task) => clients.push(RenewalHandle::new(rx, task)),
+                Err(err) => {
+                    for client in clients {
+                        let _ = client.shutdown().await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(NiocaSniHandle {
+            acceptor,
+            resolver,
+            clients,
+        })
+    }
+
+    /// Spawns the SNI renewals and only returns after each client has fetched its first
+    /// certificate.
+    ///
+    /// If not all certificates arrive within `timeout`, all renewals are stopped and an error
+    /// is returned.
+    pub async fn spawn_sni_and_wait(
+        configs: Vec<NiocaConfig>,
+        timeout: Duration,
+    ) -> Result<NiocaSniHandle, NiocaError> {
+        let handle = Self::spawn_sni(configs).await?;
+        match handle.wait_ready(timeout).await {
+            Ok(_) => Ok(handle),
+            Err(err) => {
+                let _ = handle.shutdown().await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns a `ServerConfig`, which always serves the latest certificate from the given
+    /// resolver and authenticates clients as configured.
+    pub fn server_config(
+        config: &NiocaConfig,
+        resolver: Arc<dyn ResolvesServerCert>,
+    ) -> Result<ServerConfig, NiocaError> {
+        resolver_server_config(config, resolver)
+    }
+}
+
+/// The handle for several renewal tasks, which feed a single SNI based `TlsAcceptor`.
+pub struct NiocaSniHandle {
+    acceptor: TlsAcceptor,
+    resolver: Arc<NiocaSniResolver>,
+    clients: Vec<RenewalHandle<Vec<String>>>,
+}
+
+impl NiocaSniHandle {
+    /// Returns the acceptor, which serves the matching certificate for each connection.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+
+    /// Returns the resolver, which can be used to build a customized `ServerConfig`.
+    pub fn resolver(&self) -> Arc<NiocaSniResolver> {
+        self.resolver.clone()
+    }
+
+    /// Returns the renewal handles in the order of the configs. Each receiver contains the
+    /// names the certificate of this client is currently served for.
+    pub fn clients(&self) -> &[RenewalHandle<Vec<String>>] {
+        &self.clients
+    }
+
+    /// Returns `false` if any of the renewal tasks has stopped.
+    pub fn is_running(&self) -> bool {
+        self.clients.iter().all(|client| client.is_running())
+    }
+
+    /// Waits until each client has its first certificate.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<(), NiocaError> {
+        let deadline = Instant::now() + timeout;
+        for client in &self.clients {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            client.wait_ready(remaining).await?;
+        }
+        Ok(())
+    }
+
+    /// Stops all renewals and waits for the tasks to exit.
+    ///
+    /// Returns the last error, if any of the tasks panicked before.
+    pub async fn shutdown(self) -> Result<(), NiocaError> {
+        let mut res = Ok(());
+        for client in self.clients {
+            if let Err(err) = client.shutdown().await {
+                res = Err(err);
+            }
+        }
+        res
+    }
+}
+
+/// The certificate of a single SNI slot together with the names it is served for, which are
+/// always swapped at once.
+struct SniCert {
+    key: Arc<CertifiedKey>,
+    names: Vec<String>,
+}
+
+/// A rustls `ResolvesServerCert`, which hosts the certificates of several Nioca x509
+/// clients and selects one of them by SNI.
+///
+/// Each slot is matched against the DNS names of its current certificate, including
+/// wildcards. Connections without SNI or with an unknown name get the first certificate,
+/// which is available.
+pub struct NiocaSniResolver {
+    entries: Vec<ArcSwapOption<SniCert>>,
+}
+
+impl NiocaSniResolver {
+    /// Creates a resolver with the given number of empty slots.
+    pub fn new(slots: usize) -> Self {
+        let entries = (0..slots).map(|_| ArcSwapOption::empty()).collect();
+        Self { entries }
+    }
+
+    /// Validates the given certificate and makes it the active one for the slot `idx`.
+    /// On error, the currently active certificate is kept.
+    ///
+    /// Returns the DNS names the certificate will be served for.
+    pub fn update(&self, idx: usize, certs: &CertX509Response) -> Result<Vec<String>, NiocaError> {
+        let entry = self
+            .entries
+            .get(idx)
+            .ok_or_else(|| NiocaError::Config(format!("The SNI resolver has no slot {}", idx)))?;
+
+        let key = certified_key(certs)?;
+        let leaf = key.cert.first().ok_or_else(|| {
+            NiocaError::InvalidCertificate("The certificate chain is empty".to_string())
+        })?;
+        let names = dns_names(&leaf.0)?
+            .into_iter()
+            .map(|name| name.to_lowercase())
+            .collect::<Vec<_>>();
+
+        entry.store(Some(Arc::new(SniCert {
+            key: Arc::new(key),
+            names: names.clone(),
+        })));
+        Ok(names)
+    }
+
+    /// Returns `true` as soon as each slot has its first certificate.
+    pub fn is_ready(&self) -> bool {
+        self.entries.iter().all(|entry| entry.load().is_some())
+    }
+
+    fn select(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(sni) = sni {
+            let sni = sni.to_lowercase();
+            for entry in &self.entries {
+                if let Some(cert) = &*entry.load() {
+                    if cert.names.iter().any(|name| name_matches(name, &sni)) {
+                        return Some(cert.key.clone());
+                    }
+                }
+            }
+        }
+
+        self.entries
+            .iter()
+            .find_map(|entry| entry.load().as_ref().map(|cert| cert.key.clone()))
+    }
+}
+
+impl ResolvesServerCert for NiocaSniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.select(client_hello.server_name())
+    }
+}
+
+/// Checks if a (lowercase) certificate name matches the given SNI. A wildcard only covers a
+/// single label, like in `*.example.com`.
+fn name_matches(name: &str, sni: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(suffix) => match sni.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest == suffix,
+            None => false,
+        },
+        None => name == sni,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nioca_common::tls::der_to_pem;
+    use nioca_common::x509::X509CertFormat;
+    use pretty_assertions::assert_eq;
+    use rcgen::{Certificate, CertificateParams, SanType};
+
+    /// Returns a self-signed certificate with the given SANs and its DER encoding.
+    fn cert(sans: Vec<SanType>) -> (CertX509Response, Vec<u8>) {
+        let mut params = CertificateParams::new(vec![]);
+        params.subject_alt_names = sans;
+        let cert = Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+
+        let certs = CertX509Response {
+            cert: der_to_pem("CERTIFICATE", &der),
+            cert_fingerprint: String::default(),
+            cert_chain: String::default(),
+            key: cert.serialize_private_key_pem().into(),
+            cert_format: X509CertFormat::Pem,
+            not_after: 0,
+        };
+        (certs, der)
+    }
+
+    fn dns(name: &str) -> SanType {
+        SanType::DnsName(name.to_string())
+    }
+
+    fn selected(resolver: &NiocaSniResolver, sni: Option<&str>) -> Option<Vec<u8>> {
+        resolver.select(sni).map(|key| key.cert[0].0.clone())
+    }
+
+    #[test]
+    fn test_sni_selects_slot() {
+        let resolver = NiocaSniResolver::new(2);
+        let (api, api_der) = cert(vec![dns("api.example.com")]);
+        let (web, web_der) = cert(vec![
+            dns("*.Web.example.com"),
+            SanType::Rfc822Name("admin@example.com".to_string()),
+            SanType::URI("spiffe://example.com/web".to_string()),
+            SanType::IpAddress("10.0.0.1".parse().unwrap()),
+        ]);
+
+        assert!(!resolver.is_ready());
+        assert_eq!(
+            resolver.update(0, &api).unwrap(),
+            vec!["api.example.com".to_string()]
+        );
+        // only DNS names are matched against the SNI
+        assert_eq!(
+            resolver.update(1, &web).unwrap(),
+            vec!["*.web.example.com".to_string()]
+        );
+        assert!(resolver.is_ready());
+
+        assert_eq!(selected(&resolver, Some("api.example.com")), Some(api_der));
+        assert_eq!(
+            selected(&resolver, Some("App.WEB.example.com")),
+            Some(web_der)
+        );
+    }
+
+    #[test]
+    fn test_sni_fallback() {
+        let resolver = NiocaSniResolver::new(2);
+        assert_eq!(selected(&resolver, None), None);
+
+        // the first slot, which has a certificate, is the fallback
+        let (web, web_der) = cert(vec![dns("web.example.com")]);
+        resolver.update(1, &web).unwrap();
+        assert_eq!(selected(&resolver, Some("unknown.org")), Some(web_der));
+
+        let (api, api_der) = cert(vec![dns("api.example.com")]);
+        resolver.update(0, &api).unwrap();
+        assert_eq!(selected(&resolver, None), Some(api_der.clone()));
+        assert_eq!(selected(&resolver, Some("unknown.org")), Some(api_der));
+    }
+
+    #[test]
+    fn test_sni_update_failure_keeps_cert_and_names() {
+        let resolver = NiocaSniResolver::new(1);
+        let (api, api_der) = cert(vec![dns("api.example.com")]);
+        resolver.update(0, &api).unwrap();
+
+        let (mut web, _) = cert(vec![dns("web.example.com")]);
+        web.key = api.key.clone();
+        assert!(resolver.update(0, &web).is_err());
+        assert!(resolver.update(1, &api).is_err());
+
+        assert_eq!(
+            selected(&resolver, Some("api.example.com")),
+            Some(api_der.clone())
+        );
+        let current = resolver.entries[0].load();
+        assert_eq!(
+            current.as_ref().unwrap().names,
+            vec!["api.example.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches("api.example.com", "api.example.com"));
+        assert!(!name_matches("api.example.com", "web.example.com"));
+
+        assert!(name_matches("*.example.com", "api.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.api.example.com"));
+        assert!(!name_matches("*.example.com", ".example.com"));
+    }
+}
@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use nioca_common::tls::{certs_to_pem, der_to_pem, resolver_server_config, server_cert_and_key};
+use nioca_common::{spawn_renewal, CertSink};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+use tonic::Request;
+use tracing::{debug, error};
+
+pub use nioca_common::tls::NiocaCertResolver;
+pub use nioca_common::x509::CertX509Response;
+pub use nioca_common::{
+    ClientAuth, NiocaConfig, NiocaError, PeerIdentity, RenewalHandle, RenewalStatus, Secret,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct NiocaTonic;
+
+/// All TLS configs built from the latest certificate.
+#[derive(Clone)]
+pub struct NiocaTonicTls {
+    /// A `TlsAcceptor` backed by a [NiocaCertResolver], which always serves the latest
+    /// certificate. Use it with [NiocaTonic::incoming] for a server which never needs a
+    /// restart.
+    pub acceptor: TlsAcceptor,
+    /// A tonic `ServerTlsConfig` with the current certificate. tonic builds its TLS config
+    /// once during start, which means a server using it must be restarted to pick up a
+    /// renewed certificate.
+    pub server: ServerTlsConfig,
+    /// A tonic `ClientTlsConfig`, which trusts the Nioca root and presents the current
+    /// certificate as client identity. Channels must be recreated to use a renewed
+    /// certificate.
+    pub channel: ClientTlsConfig,
+}
+
+/// Swaps each renewed certificate into the resolver and rebuilds the tonic configs.
+struct Sink {
+    resolver: Arc<NiocaCertResolver>,
+    acceptor: TlsAcceptor,
+    root: Option<Certificate>,
+    client_auth: ClientAuth,
+    tx: watch::Sender<Option<NiocaTonicTls>>,
+}
+
+#[async_trait]
+impl CertSink for Sink {
+    async fn accept(&mut self, certs: &CertX509Response) -> Result<(), NiocaError> {
+        let identity = NiocaTonic::identity(certs)?;
+        self.resolver.update(certs)?;
+
+        let mut server = ServerTlsConfig::new().identity(identity.clone());
+        if let Some(root) = &self.root {
+            if self.client_auth != ClientAuth::None {
+                server = server
+                    .client_ca_root(root.clone())
+                    .client_auth_optional(self.client_auth == ClientAuth::Optional);
+            }
+        }
+
+        let mut channel = ClientTlsConfig::new().identity(identity);
+        if let Some(root) = &self.root {
+            channel = channel.ca_certificate(root.clone());
+        }
+
+        self.tx.send_replace(Some(NiocaTonicTls {
+            acceptor: self.acceptor.clone(),
+            server,
+            channel,
+        }));
+        Ok(())
+    }
+}
+
+impl NiocaTonic {
+    /// Spawns the certificate renewal.
+    ///
+    /// The [NiocaTonicTls] is published as soon as the first certificate is available and
+    /// rebuilt with each renewal. Its `acceptor` stays the same and picks up new certificates
+    /// in place.
+    ///
+    /// If `NiocaConfig::client_auth` is set, servers verify client certificates against the
+    /// Nioca root. Use [NiocaTonic::peer_identity] to access them inside a handler.
+    pub async fn spawn(config: NiocaConfig) -> Result<RenewalHandle<NiocaTonicTls>, NiocaError> {
+        Self::spawn_with_resolver(config, Arc::new(NiocaCertResolver::new())).await
+    }
+
+    /// Same as [NiocaTonic::spawn], but updates the given resolver.
+    pub async fn spawn_with_resolver(
+        config: NiocaConfig,
+        resolver: Arc<NiocaCertResolver>,
+    ) -> Result<RenewalHandle<NiocaTonicTls>, NiocaError> {
+        let acceptor = TlsAcceptor::from(Arc::new(Self::server_config(&config, resolver.clone())?));
+        let root = config
+            .root_pem
+            .as_ref()
+            .map(|pem| Certificate::from_pem(pem.as_bytes()));
+
+        let (tx, rx) = watch::channel(None);
+        let sink = Sink {
+            resolver,
+            acceptor,
+            root,
+            client_auth: config.client_auth,
+            tx,
+        };
+        let task = spawn_renewal(config, sink)?;
+        Ok(RenewalHandle::new(rx, task))
+    }
+
+    /// Spawns the renewal and waits for the first certificate, see
+    /// [RenewalHandle::wait_ready].
+    pub async fn spawn_and_wait(
+        config: NiocaConfig,
+        timeout: Duration,
+    ) -> Result<RenewalHandle<NiocaTonicTls>, NiocaError> {
+        Self::spawn(config).await?.into_ready(timeout).await
+    }
+
+    /// Returns a `ServerConfig` for gRPC, which always serves the latest certificate from the
+    /// given resolver and authenticates clients as configured.
+    pub fn server_config(
+        config: &NiocaConfig,
+        resolver: Arc<NiocaCertResolver>,
+    ) -> Result<ServerConfig, NiocaError> {
+        let mut cfg = resolver_server_config(config, resolver)?;
+        // gRPC only works over HTTP/2
+        cfg.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(cfg)
+    }
+
+    /// Converts the given certificate into a tonic `Identity`.
+    ///
+    /// The chain is ordered leaf first and the private key is checked to belong to the leaf.
+    pub fn identity(certs: &CertX509Response) -> Result<Identity, NiocaError> {
+        let (chain, key) = server_cert_and_key(certs)?;
+        let key = Secret::new(der_to_pem("PRIVATE KEY", &key.0));
+        Ok(Identity::from_pem(
+            certs_to_pem(&chain),
+            key.expose_secret(),
+        ))
+    }
+
+    /// Accepts TCP connections on the given listener and performs the TLS handshake with the
+    /// given acceptor. Pass the returned stream to `Server::serve_with_incoming`.
+    ///
+    /// Failed handshakes are logged and skipped, they never end the stream. The listener is
+    /// closed as soon as the stream is dropped.
+    pub fn incoming(
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    ) -> ReceiverStream<Result<TlsStream<TcpStream>, io::Error>> {
+        let (tx, rx) = mpsc::channel(32);
+
+        tokio::spawn(async move {
+            loop {
+                let res = tokio::select! {
+                    _ = tx.closed() => break,
+                    res = listener.accept() => res,
+                };
+                let (stream, addr) = match res {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!("Error accepting TCP connection: {}", err);
+                        time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => {
+                            let _ = tx.send(Ok(tls)).await;
+                        }
+                        Ok(Err(err)) => debug!("TLS handshake with {} failed: {}", addr, err),
+                        Err(_) => debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    /// Returns the verified client certificate of the connection the request came in on.
+    ///
+    /// Works with connections from [NiocaTonic::incoming] as well as with tonic's own TLS.
+    pub fn peer_identity<T>(req: &Request<T>) -> Option<PeerIdentity> {
+        let certs = req.peer_certs()?;
+        PeerIdentity::from_der(certs.first()?.get_ref()).ok()
+    }
+}
@@ -0,0 +1,128 @@
+use nioca_common::fingerprint;
+use nioca_common::tls::{der_to_pem, pem_to_certs, pem_to_private_key};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub struct Leaf {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub der: Vec<u8>,
+}
+
+pub fn ca(name: &str) -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+/// Returns a certificate with the given common name and SANs, signed by `root`.
+pub fn leaf(root: &Certificate, name: &str, sans: &[&str]) -> Leaf {
+    let sans = sans.iter().map(|san| san.to_string()).collect::<Vec<_>>();
+    let mut params = CertificateParams::new(sans);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = Certificate::from_params(params).unwrap();
+    let der = cert.serialize_der_with_signer(root).unwrap();
+    Leaf {
+        cert_pem: der_to_pem("CERTIFICATE", &der),
+        key_pem: cert.serialize_private_key_pem(),
+        der,
+    }
+}
+
+/// Starts a fake Nioca, which answers with the given leaves in order and repeats the last one.
+pub async fn mock_nioca(
+    root: &Certificate,
+    leaves: Vec<Leaf>,
+    fetches: Arc<AtomicUsize>,
+) -> SocketAddr {
+    let server = leaf(root, "Nioca", &["localhost"]);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            pem_to_certs(&server.cert_pem).unwrap(),
+            pem_to_private_key(&server.key_pem).unwrap(),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let root_pem = root.serialize_pem().unwrap();
+    let leaves = Arc::new(leaves);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let Ok(mut tls) = acceptor.accept(stream).await else {
+                continue;
+            };
+            if read_request(&mut tls).await.is_none() {
+                continue;
+            }
+
+            let idx = fetches.fetch_add(1, Ordering::SeqCst).min(leaves.len() - 1);
+            let leaf = &leaves[idx];
+            let not_after = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 2;
+            let body = format!(
+                r#"{{"cert":"{}","certFingerprint":"{}","certChain":"{}","key":"{}","certFormat":"PEM","notAfter":{}}}"#,
+                leaf.cert_pem.replace('\n', "\\n"),
+                fingerprint(&leaf.der),
+                root_pem.replace('\n', "\\n"),
+                leaf.key_pem.replace('\n', "\\n"),
+                not_after,
+            );
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = tls.write_all(resp.as_bytes()).await;
+            let _ = tls.shutdown().await;
+        }
+    });
+
+    addr
+}
+
+/// Reads a single HTTP/1.1 request including its body.
+async fn read_request<S: AsyncReadExt + Unpin>(stream: &mut S) -> Option<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_lowercase();
+    let content_length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() - head_end < content_length {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    Some(())
+}
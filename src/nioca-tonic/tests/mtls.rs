@@ -0,0 +1,181 @@
+mod common;
+
+use bytes::{Buf, BufMut};
+use common::{ca, leaf, mock_nioca};
+use nioca_tonic::{ClientAuth, NiocaConfig, NiocaTonic};
+use pretty_assertions::assert_eq;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{NamedService, UnaryService};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Server};
+use tonic::{Request, Response, Status};
+
+/// Plain UTF-8 messages, which saves us a protobuf build for the tests.
+#[derive(Clone, Copy, Default)]
+struct StringCodec;
+
+impl Codec for StringCodec {
+    type Encode = String;
+    type Decode = String;
+    type Encoder = Self;
+    type Decoder = Self;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        *self
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        *self
+    }
+}
+
+impl Encoder for StringCodec {
+    type Item = String;
+    type Error = Status;
+
+    fn encode(&mut self, item: String, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(item.as_bytes());
+        Ok(())
+    }
+}
+
+impl Decoder for StringCodec {
+    type Item = String;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<String>, Status> {
+        let bytes = src.copy_to_bytes(src.remaining());
+        String::from_utf8(bytes.to_vec())
+            .map(Some)
+            .map_err(|err| Status::invalid_argument(err.to_string()))
+    }
+}
+
+/// A gRPC service, which answers with the subject and SANs of the client certificate.
+#[derive(Clone)]
+struct Whoami;
+
+impl NamedService for Whoami {
+    const NAME: &'static str = "nioca.Whoami";
+}
+
+impl UnaryService<String> for Whoami {
+    type Response = String;
+    type Future = BoxFuture<Response<String>, Status>;
+
+    fn call(&mut self, req: Request<String>) -> Self::Future {
+        let peer = NiocaTonic::peer_identity(&req);
+        Box::pin(async move {
+            let peer = peer.ok_or_else(|| Status::unauthenticated("no client certificate"))?;
+            Ok(Response::new(format!(
+                "{} {}",
+                peer.subject,
+                peer.sans.join(",")
+            )))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for Whoami
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let svc = self.clone();
+        Box::pin(async move { Ok(tonic::server::Grpc::new(StringCodec).unary(svc, req).await) })
+    }
+}
+
+fn config(nioca_addr: SocketAddr, root_pem: &str) -> NiocaConfig {
+    NiocaConfig::builder()
+        .url(format!("https://localhost:{}", nioca_addr.port()))
+        .client_id_x509("test")
+        .api_key_x509("secret")
+        .root_pem(root_pem)
+        .client_auth(ClientAuth::Required)
+        .build()
+        .unwrap()
+}
+
+async fn whoami(port: u16, tls: ClientTlsConfig) -> Result<String, Status> {
+    let channel = Channel::from_shared(format!("https://localhost:{}", port))
+        .unwrap()
+        .tls_config(tls)
+        .unwrap()
+        .connect()
+        .await
+        .map_err(|err| Status::unavailable(err.to_string()))?;
+
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready()
+        .await
+        .map_err(|err| Status::unavailable(err.to_string()))?;
+    let resp = grpc
+        .unary(
+            Request::new("who am i".to_string()),
+            PathAndQuery::from_static("/nioca.Whoami/Get"),
+            StringCodec,
+        )
+        .await?;
+    Ok(resp.into_inner())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mtls_with_incoming_and_channel() {
+    let root = ca("Nioca Test Root");
+    let root_pem = root.serialize_pem().unwrap();
+    let server = leaf(&root, "Server", &["localhost"]);
+    let client = leaf(&root, "my-client", &["client.local", "10.0.0.1"]);
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let server_nioca = mock_nioca(&root, vec![server], fetches.clone()).await;
+    let client_nioca = mock_nioca(&root, vec![client], fetches).await;
+
+    let server_handle =
+        NiocaTonic::spawn_and_wait(config(server_nioca, &root_pem), Duration::from_secs(10))
+            .await
+            .unwrap();
+    let client_handle =
+        NiocaTonic::spawn_and_wait(config(client_nioca, &root_pem), Duration::from_secs(10))
+            .await
+            .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acceptor = server_handle.receiver().borrow().clone().unwrap().acceptor;
+    tokio::spawn(
+        Server::builder()
+            .add_service(Whoami)
+            .serve_with_incoming(NiocaTonic::incoming(listener, acceptor)),
+    );
+
+    let channel_tls = client_handle.receiver().borrow().clone().unwrap().channel;
+    assert_eq!(
+        whoami(port, channel_tls).await.unwrap(),
+        "CN=my-client client.local,10.0.0.1"
+    );
+
+    // the handshake fails without a client certificate
+    let without_cert =
+        ClientTlsConfig::new().ca_certificate(Certificate::from_pem(root_pem.as_bytes()));
+    assert!(whoami(port, without_cert).await.is_err());
+
+    client_handle.shutdown().await.unwrap();
+    server_handle.shutdown().await.unwrap();
+}